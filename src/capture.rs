@@ -0,0 +1,80 @@
+//! `nb capture`: note something down without opening an editor, as a
+//! timestamped line in the inbox card or as a small card of its own.
+
+use anyhow::Result;
+use chrono::{Local, SecondsFormat};
+use std::path::PathBuf;
+
+use crate::frontmatter::FrontMatter;
+use crate::heap::Heap;
+
+/// Timestamp leading each line in the inbox
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// Tag on the inbox and on cards created by a capture
+const INBOX_TAG: &str = "inbox";
+
+/// Append `text` to the inbox as one line, creating the inbox if need be,
+/// or with `new` write it to a new card. Either way it's committed and
+/// indexed. Returns the path of the card written.
+pub fn capture(heap: &mut Heap, text: &str, new: bool) -> Result<PathBuf> {
+    let now = Local::now();
+
+    if new {
+        let mut frontmatter = FrontMatter::default();
+        frontmatter.set("created", &now.to_rfc3339_opts(SecondsFormat::Secs, false));
+        frontmatter.set("tags", INBOX_TAG);
+        return heap.create_card(None::<PathBuf>, &frontmatter.render(&format!("{}\n", text.trim())));
+    }
+
+    let inbox = PathBuf::from(heap.config()?.capture.inbox);
+    // Keep to one line so each entry stays a single list item
+    let line = format!("- {} {}\n", now.format(TIME_FORMAT), text.split_whitespace().collect::<Vec<_>>().join(" "));
+
+    if heap.path().join(&inbox).exists() {
+        let mut content = heap.read_card(&inbox)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&line);
+        heap.update_card(&inbox, &content)?;
+    } else {
+        let mut frontmatter = FrontMatter::default();
+        frontmatter.set("title", "Inbox");
+        frontmatter.set("tags", INBOX_TAG);
+        heap.create_card(Some(&inbox), &frontmatter.render(&line))?;
+    }
+
+    Ok(inbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_lines() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+
+        let inbox = capture(&mut heap, "buy milk", false)?;
+        capture(&mut heap, "call\nthe  plumber", false)?;
+        assert_eq!(inbox, PathBuf::from("inbox.md"));
+
+        let content = heap.read_card(&inbox)?;
+        let (fm, body) = FrontMatter::parse(&content);
+        assert_eq!(fm.unwrap().tags(), vec![INBOX_TAG]);
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", body);
+        assert!(lines[0].starts_with("- ") && lines[0].ends_with(" buy milk"));
+        assert!(lines[1].ends_with(" call the plumber"));
+        assert_eq!(heap.find("plumber")?[0].card.path, "inbox.md");
+
+        let card = capture(&mut heap, "an idea worth its own card", true)?;
+        assert_ne!(card, inbox);
+        assert_eq!(heap.find("worth")?[0].card.path, card.to_string_lossy());
+        assert_eq!(heap.find(&format!("tags:{}", INBOX_TAG))?.len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+/// A card as search results and exports refer to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Card {
+    pub path: String,                        // Relative to the heap
+    pub attributes: HashMap<String, String>, // Front matter, where it's been read
+}
@@ -1,53 +1,201 @@
-use serde::Deserialize;
+//! Per-heap settings, read from `.nb/config.toml`. Every setting has a
+//! default, so the file and any of its sections may be left out:
+//!
+//! ```toml
+//! [cards]
+//! include = ["*.md", "*.txt"]
+//! exclude = ["drafts/**"]
+//!
+//! [journal]
+//! path = "journal/%Y/%Y-%m-%d.md"
+//! template = "# {{date}}\n\n"
+//!
+//! [capture]
+//! inbox = "inbox.md"
+//!
+//! [search]
+//! worktree = false
+//! ```
+
 use anyhow::{Context, Result};
-use std::fs::File;
-use std::io::Read;
-use std::path::{PathBuf, Path};
-use log::{warn};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::path::Path;
 
-#[derive(Deserialize)]
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Ignore files read from the root of a heap, or of a folder being
+/// imported. `.nbignore` uses the `.gitignore` syntax, for files kept in
+/// git that still aren't cards.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".nbignore"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub state: PathBuf,
-    pub index: PathBuf,
-    pub notes: PathBuf,
+    pub cards: Cards,
+    pub journal: Journal,
+    pub capture: Capture,
+    pub search: Search,
+}
+
+/// Globs, relative to the heap root, picking which files are cards
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cards {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Default for Cards {
+    fn default() -> Cards {
+        Cards {
+            include: vec!["*.md".to_owned(), "*.txt".to_owned()],
+            exclude: vec!(),
+        }
+    }
+}
+
+/// Where `nb journal` keeps its entries
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Journal {
+    pub path: String,     // strftime pattern, relative to the heap
+    pub template: String, // Content of new entries, with {{date}} filled in
+}
+
+impl Default for Journal {
+    fn default() -> Journal {
+        Journal {
+            path: "journal/%Y/%Y-%m-%d.md".to_owned(),
+            template: "---\ntitle: {{date}}\ntags: journal\n---\n\n".to_owned(),
+        }
+    }
+}
+
+/// Where `nb capture` puts what it's given
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capture {
+    pub inbox: String, // Card captured lines are appended to, relative to the heap
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture { inbox: "inbox.md".to_owned() }
+    }
+}
+
+/// What `nb search` looks at besides the committed cards
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Search {
+    pub worktree: bool, // Also search uncommitted changes to cards, as they are in the worktree
 }
 
 impl Config {
-    #[allow(dead_code)]
-    fn new(index: &str, notes: &str, state: &str) -> Result<Config> {
-        return Ok(Config {
-            state: Path::new(state).to_owned(),
-            index: Path::new(index).to_owned(),
-            notes: Path::new(notes).to_owned(),
-        });
-    }
-
-    fn default() -> Config {
-        return Config {
-            state: Path::new("/tmp/nb").to_owned(),
-            index: Path::new("/tmp/nb/index").to_owned(),
-            notes: Path::new("/tmp/notes").to_owned(),
+    /// Load the config at `path`, or the defaults if there isn't one
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let config_toml = match std::fs::read_to_string(path) {
+            Ok(config_toml) => config_toml,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e).with_context(|| format!("Error while reading config {}", path.display())),
         };
+        toml::from_str(&config_toml)
+            .with_context(|| format!("Failed to decode config file {}", path.display()))
     }
+}
 
-    /// Attempt to load and parse the config file into our Config struct.
-    /// If a file cannot be found, return a default Config.
-    /// If we find a file but cannot parse it, panic
-    pub fn open_or_create<P: AsRef<Path>>(path: P) -> anyhow::Result<Config> {
-        let mut config_toml = String::new();
+/// Decides which files under a root are cards: those matching an include
+/// glob and no exclude glob, and not ignored by the root's ignore files
+#[derive(Debug)]
+pub struct CardFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+    ignore: Gitignore,
+    fingerprint: String, // Hash of the globs and ignore files
+}
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
-            Err(_) => {
-                warn!("Could not find config file, using embedded default");
-                // TODO: Generate config file
-                return Ok(Config::default());
+impl CardFilter {
+    pub fn new(cards: &Cards, root: &Path) -> Result<CardFilter> {
+        let mut inputs = format!("{:?}\n{:?}\n", cards.include, cards.exclude).into_bytes();
+        let mut ignore = GitignoreBuilder::new(root);
+        for name in IGNORE_FILES {
+            let path = root.join(name);
+            if path.is_file() {
+                if let Some(e) = ignore.add(&path) {
+                    log::warn!("{}: {}", path.display(), e);
+                }
+                inputs.extend(format!("{}\n", name).bytes());
+                inputs.extend(std::fs::read(&path)?);
             }
-        };
-        // let file = File::open(&path)
-        //     .with_context(|| format!("Error while opening config {}", path))?;
-        file.read_to_string(&mut config_toml)
-            .with_context(|| format!("Error while reading config {}", path.as_ref().to_string_lossy()))?;
-        return toml::from_str(&config_toml).context("Failed to decode config file");
+        }
+
+        Ok(CardFilter {
+            include: glob_set(&cards.include)?,
+            exclude: glob_set(&cards.exclude)?,
+            ignore: ignore.build()?,
+            fingerprint: format!("{:x}", md5::compute(&inputs)),
+        })
+    }
+
+    /// Changes whenever the filter might pick different cards
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Whether `path`, relative to the root, is a card
+    pub fn is_card(&self, path: &Path) -> bool {
+        self.include.is_match(path) && !self.is_excluded(path)
+    }
+
+    /// Whether `path`, relative to the root, is excluded by a glob or an
+    /// ignore file, whatever its type
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+            || self.ignore.matched_path_or_any_parents(path, false).is_ignore()
+    }
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for glob in globs {
+        set.add(Glob::new(glob).with_context(|| format!("Invalid glob in config: {}", glob))?);
     }
-}
\ No newline at end of file
+    Ok(set.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_cards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join(".gitignore"), ".nb\n*.tmp.md\n")?;
+        std::fs::write(dir.path().join(".nbignore"), "archive/\n")?;
+        std::fs::write(dir.path().join("config.toml"), "[cards]\nexclude = [\"drafts/**\"]\n")?;
+
+        let config = Config::open(dir.path().join("config.toml"))?;
+        let filter = CardFilter::new(&config.cards, dir.path())?;
+
+        for (path, is_card) in &[
+            ("note.md", true),
+            ("projects/list.txt", true),
+            (".gitignore", false),
+            ("photo.png", false),
+            ("scratch.tmp.md", false),
+            ("archive/2019/old.md", false),
+            ("drafts/idea.md", false),
+        ] {
+            assert_eq!(filter.is_card(Path::new(path)), *is_card, "{}", path);
+        }
+
+        assert!(Config::open(dir.path().join("missing.toml"))?.cards.include.contains(&"*.md".to_owned()));
+        std::fs::write(dir.path().join("bad.toml"), "[cards]\ninclude = \"*.md\"\n")?;
+        assert!(Config::open(dir.path().join("bad.toml")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,551 @@
+//! `nb export`: write the heap, or part of it, out in other formats.
+//!
+//! The HTML export is a static site: one page per card with its `[[links]]`
+//! turned into relative hyperlinks and a backlinks section, a page per tag,
+//! an index page and `search-index.json` for client-side search. Cards are
+//! read as of the last commit, so exporting the same commit twice gives the
+//! same files. Raw HTML in cards is shown as text rather than passed
+//! through, and links and images are kept to http, https, mailto and
+//! relative urls, so a published site can't carry scripts from a card.
+//!
+//! The JSONL export is an archive of every committed card and attachment,
+//! one `Record` per line, for backups, moving notes between heaps and
+//! scripting. `nb import jsonl` restores it.
+
+use anyhow::{bail, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC, CONTROLS};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::card::Card;
+use crate::frontmatter::FrontMatter;
+use crate::heap::{self, Heap};
+use crate::links;
+use crate::text;
+
+/// Left in the output directory so a later export knows it may replace it
+const MARKER: &str = ".nb-export";
+const SEARCH_INDEX: &str = "search-index.json";
+const INDEX_PAGE: &str = "index.html";
+const TAGS_DIR: &str = "tags";
+
+/// Url schemes links and images may use; anything else, such as
+/// `javascript:`, is dropped
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Characters escaped in each segment of an href
+const HREF: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`');
+
+/// A card as it appears in the site
+#[derive(Debug)]
+struct Page {
+    card: PathBuf,   // Path of the card, relative to the heap
+    output: PathBuf, // Path of the page, relative to the output directory
+    title: String,
+    tags: Vec<String>,
+    body: String,    // Markdown without front matter
+}
+
+/// A card or attachment in a JSONL archive. Its contents are kept exactly
+/// as committed; the attributes and links are read from them for scripts,
+/// and ignored when restoring.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub id: String, // Key links to the card use
+    #[serde(flatten)]
+    pub card: Card, // Path and front matter attributes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>, // Contents of cards that are UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>, // Base64 contents of attachments and other cards
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    #[serde(default)]
+    pub links: Vec<String>, // Keys of the cards linked to
+}
+
+#[derive(Debug, Serialize)]
+struct SearchEntry<'a> {
+    url: String,
+    title: &'a str,
+    tags: &'a [String],
+    text: &'a str,
+}
+
+/// Export cards as a static HTML site into `dir`. With `tag`, only cards
+/// tagged with it are exported and links to other cards become plain text.
+/// Returns the number of cards exported.
+pub fn html(heap: &Heap, dir: &Path, tag: Option<&str>) -> Result<usize> {
+    prepare_dir(dir)?;
+
+    let mut pages = vec!();
+    for card in heap.cards()? {
+        let content = match text::decode(&heap.read_committed(&card)?).into_string() {
+            Some(content) => content,
+            None => continue,
+        };
+        let page = Page::new(card, &content);
+        if tag.is_none_or(|tag| page.tags.iter().any(|t| t == tag)) {
+            pages.push(page);
+        }
+    }
+    unique_outputs(&mut pages)?;
+
+    let by_card: BTreeMap<&Path, &Page> = pages.iter().map(|p| (p.card.as_path(), p)).collect();
+
+    // Links between exported cards, resolved once for both directions
+    let mut outgoing: BTreeMap<&Path, Vec<(links::Link, Option<&Page>)>> = BTreeMap::new();
+    let mut backlinks: BTreeMap<&Path, BTreeSet<&Path>> = BTreeMap::new();
+    for page in &pages {
+        let resolved = links::extract(&page.body).into_iter()
+            .map(|link| {
+                let target = heap.resolve_link(&link.key())
+                    .and_then(|path| by_card.get(path.as_path()).copied());
+                if let Some(target) = target {
+                    backlinks.entry(&target.card).or_default().insert(&page.card);
+                }
+                (link, target)
+            })
+            .collect();
+        outgoing.insert(&page.card, resolved);
+    }
+
+    let mut tags: BTreeMap<&str, Vec<&Page>> = BTreeMap::new();
+    for page in &pages {
+        for tag in &page.tags {
+            tags.entry(tag).or_default().push(page);
+        }
+    }
+
+    for page in &pages {
+        let markdown = replace_links(page, &outgoing[page.card.as_path()]);
+        let mut body = String::new();
+        let events = Parser::new_ext(&markdown, markdown_options()).map(|event| match event {
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            Event::Start(Tag::Link { link_type, dest_url, title, id }) =>
+                Event::Start(Tag::Link { link_type, dest_url: safe_url(dest_url), title, id }),
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) =>
+                Event::Start(Tag::Image { link_type, dest_url: safe_url(dest_url), title, id }),
+            event => event,
+        });
+        html::push_html(&mut body, events);
+
+        let mut html = format!("<article>\n{}</article>\n", body);
+
+        if !page.tags.is_empty() {
+            html.push_str("<p class=\"tags\">");
+            for tag in &page.tags {
+                html.push_str(&format!("<a href=\"{}\">#{}</a> ",
+                    href(&page.output, &tag_page(tag)), escape(tag)));
+            }
+            html.push_str("</p>\n");
+        }
+
+        if let Some(sources) = backlinks.get(page.card.as_path()) {
+            html.push_str("<section class=\"backlinks\">\n<h2>Backlinks</h2>\n<ul>\n");
+            for source in sources {
+                html.push_str(&list_item(&page.output, by_card[source]));
+            }
+            html.push_str("</ul>\n</section>\n");
+        }
+
+        write_page(dir, &page.output, &page.title, &html)?;
+    }
+
+    for (tag, tagged) in &tags {
+        let output = tag_page(tag);
+        let mut html = format!("<h1>#{}</h1>\n<ul>\n", escape(tag));
+        for page in tagged {
+            html.push_str(&list_item(&output, page));
+        }
+        html.push_str("</ul>\n");
+        write_page(dir, &output, &format!("#{}", tag), &html)?;
+    }
+
+    write_page(dir, Path::new(INDEX_PAGE), "Index", &index_page(&pages, &tags))?;
+
+    let entries: Vec<SearchEntry> = pages.iter()
+        .map(|page| SearchEntry {
+            url: href(Path::new(INDEX_PAGE), &page.output),
+            title: &page.title,
+            tags: &page.tags,
+            text: &page.body,
+        })
+        .collect();
+    std::fs::write(dir.join(SEARCH_INDEX), serde_json::to_string(&entries)?)?;
+
+    Ok(pages.len())
+}
+
+impl Page {
+    fn new(card: PathBuf, content: &str) -> Page {
+        let (frontmatter, body) = FrontMatter::parse(content);
+        let tags = frontmatter.as_ref().map(|fm| fm.tags()).unwrap_or_default();
+
+        let title = frontmatter.as_ref()
+            .and_then(|fm| fm.get("title"))
+            .map(str::to_owned)
+            .or_else(|| body.lines()
+                .find_map(|line| line.strip_prefix("# "))
+                .map(|title| title.trim().to_owned()))
+            .unwrap_or_else(|| links::key(card.file_name().unwrap_or_default()));
+
+        Page {
+            output: card.with_extension("html"),
+            card,
+            title,
+            tags,
+            body: body.to_owned(),
+        }
+    }
+}
+
+/// Cards whose pages would share a name, such as `a.md` and `a.txt`, or
+/// take the name of a page of the export's own, such as `index.md` or
+/// `tags/fruit.md`, keep their extensions in it instead: `a.md.html`,
+/// `a.txt.html`, `index.md.html`. Fails if names still clash after that.
+fn unique_outputs(pages: &mut [Page]) -> Result<()> {
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    for page in pages.iter() {
+        *counts.entry(page.output.clone()).or_default() += 1;
+    }
+
+    for page in pages.iter_mut() {
+        if counts[&page.output] > 1 || is_generated(&page.output) {
+            let mut output = page.card.clone().into_os_string();
+            output.push(".html");
+            page.output = PathBuf::from(output);
+        }
+    }
+
+    let mut outputs = HashSet::new();
+    for page in pages.iter() {
+        if !outputs.insert(&page.output) || is_generated(&page.output) {
+            bail!("Can't export {}, its page would be {}, which another page needs",
+                page.card.display(), page.output.display());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `output` is the index or could be a tag page, whose names are
+/// only ever letters, digits and `%`
+fn is_generated(output: &Path) -> bool {
+    let tag_page = output.parent() == Some(Path::new(TAGS_DIR))
+        && output.extension().is_some_and(|ext| ext == "html")
+        && output.file_stem().and_then(|stem| stem.to_str())
+            .is_some_and(|stem| stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '%'));
+    output == Path::new(INDEX_PAGE) || tag_page
+}
+
+/// `url` if it's relative or uses one of `SAFE_SCHEMES`, otherwise a link
+/// to nowhere
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    // Browsers ignore whitespace and control characters in a scheme, as in
+    // `java\tscript:`
+    let cleaned: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control()).collect();
+    let scheme = cleaned.find([':', '/', '?', '#'])
+        .filter(|&end| cleaned[end..].starts_with(':'))
+        .map(|end| cleaned[..end].to_ascii_lowercase());
+
+    match scheme {
+        Some(scheme) if !SAFE_SCHEMES.contains(&scheme.as_str()) => CowStr::Borrowed("#"),
+        _ => url,
+    }
+}
+
+/// Make sure `dir` is empty or holds a previous export, which is removed
+fn prepare_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        let empty = std::fs::read_dir(dir)?.next().is_none();
+        if !empty {
+            if !dir.join(MARKER).exists() {
+                bail!("{} is not empty and isn't a previous export", dir.display());
+            }
+            std::fs::remove_dir_all(dir)
+                .with_context(|| format!("Failed to remove previous export in {}", dir.display()))?;
+        }
+    }
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(MARKER), "")?;
+    Ok(())
+}
+
+fn markdown_options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS | Options::ENABLE_FOOTNOTES
+}
+
+/// Markdown of `page` with each `[[link]]` replaced by a Markdown link to
+/// the exported card, or by its text when the target isn't exported
+fn replace_links(page: &Page, links: &[(links::Link, Option<&Page>)]) -> String {
+    let mut markdown = String::with_capacity(page.body.len());
+    let mut offset = 0;
+
+    for (link, target) in links {
+        markdown.push_str(&page.body[offset..link.start]);
+        let text = link.label.as_deref().unwrap_or(&link.target);
+        match target {
+            Some(target) => markdown.push_str(&format!("[{}](<{}>)",
+                text.replace('[', "\\[").replace(']', "\\]"),
+                href(&page.output, &target.output))),
+            None => markdown.push_str(text),
+        }
+        offset = link.end;
+    }
+
+    markdown.push_str(&page.body[offset..]);
+    markdown
+}
+
+fn index_page(pages: &[Page], tags: &BTreeMap<&str, Vec<&Page>>) -> String {
+    let index = Path::new(INDEX_PAGE);
+    let mut html = String::from(concat!(
+        "<h1>Index</h1>\n",
+        "<p><input type=\"search\" id=\"search\" placeholder=\"search\"></p>\n",
+        "<ul id=\"results\"></ul>\n",
+        "<h2>Cards</h2>\n<ul>\n"));
+
+    for page in pages {
+        html.push_str(&list_item(index, page));
+    }
+    html.push_str("</ul>\n");
+
+    if !tags.is_empty() {
+        html.push_str("<h2>Tags</h2>\n<ul>\n");
+        for (tag, tagged) in tags {
+            html.push_str(&format!("<li><a href=\"{}\">#{}</a> ({})</li>\n",
+                href(index, &tag_page(tag)), escape(tag), tagged.len()));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str(SEARCH_SCRIPT);
+    html
+}
+
+/// Filters `search-index.json` as you type. Browsers won't fetch it from
+/// `file://` urls, so the box only works once the site is served.
+const SEARCH_SCRIPT: &str = r#"<script>
+const box = document.getElementById("search");
+const results = document.getElementById("results");
+let entries = null;
+box.oninput = async () => {
+  entries = entries || await (await fetch("search-index.json")).json();
+  const terms = box.value.toLowerCase().split(/\s+/).filter((t) => t);
+  results.replaceChildren();
+  if (!terms.length) return;
+  for (const entry of entries) {
+    const text = (entry.title + " " + entry.tags.join(" ") + " " + entry.text).toLowerCase();
+    if (!terms.every((t) => text.includes(t))) continue;
+    const li = document.createElement("li");
+    const a = document.createElement("a");
+    a.href = entry.url;
+    a.textContent = entry.title;
+    li.append(a);
+    results.append(li);
+  }
+};
+</script>
+"#;
+
+fn list_item(from: &Path, page: &Page) -> String {
+    format!("<li><a href=\"{}\">{}</a></li>\n", href(from, &page.output), escape(&page.title))
+}
+
+fn write_page(dir: &Path, output: &Path, title: &str, body: &str) -> Result<()> {
+    let path = dir.join(output);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let html = format!(concat!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n",
+        "<title>{}</title>\n</head>\n<body>\n<nav><a href=\"{}\">Index</a></nav>\n{}</body>\n</html>\n"),
+        escape(title), href(output, Path::new(INDEX_PAGE)), body);
+
+    std::fs::write(&path, html)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Page listing the cards tagged `tag`. The tag is encoded so any tag makes
+/// a single, distinct file name.
+fn tag_page(tag: &str) -> PathBuf {
+    Path::new(TAGS_DIR).join(format!("{}.html", utf8_percent_encode(tag, NON_ALPHANUMERIC)))
+}
+
+/// Relative url of the page at `to` from the page at `from`, both relative
+/// to the output directory
+fn href(from: &Path, to: &Path) -> String {
+    let depth = from.parent().map_or(0, |parent| parent.components().count());
+    let segments: Vec<String> = to.components()
+        .map(|c| utf8_percent_encode(&c.as_os_str().to_string_lossy(), HREF).to_string())
+        .collect();
+    format!("{}{}", "../".repeat(depth), segments.join("/"))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write every committed card and attachment to `out` as JSON lines.
+/// Timestamps come from the history of each file. Returns the number of
+/// records written.
+pub fn jsonl(heap: &Heap, out: &mut dyn Write) -> Result<usize> {
+    let history = heap.history()?;
+    let timestamp = |seconds: i64| Utc.timestamp_opt(seconds, 0).single()
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    let mut paths = heap.files()?;
+    paths.sort();
+    let mut count = 0;
+
+    for path in paths {
+        // Dotfiles such as .gitignore belong to the heap, not the notes
+        if path.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.')) {
+            continue;
+        }
+
+        let data = heap.read_committed(&path)?;
+        let times = history.get(&path);
+        let mut record = Record {
+            id: links::key(&path),
+            card: Card { path: path.to_string_lossy().into_owned(), attributes: HashMap::new() },
+            content: None,
+            data: None,
+            created: times.and_then(|(created, _)| timestamp(*created)),
+            modified: times.and_then(|(_, modified)| timestamp(*modified)),
+            links: vec!(),
+        };
+
+        let text = if heap::is_attachment(&path) { None } else { text::decode(&data).into_string() };
+        if let Some(text) = text {
+            let (frontmatter, _) = FrontMatter::parse(&text);
+            if let Some(frontmatter) = frontmatter {
+                record.card.attributes = frontmatter.attributes()
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect();
+            }
+            for link in links::extract(&text) {
+                if !record.links.contains(&link.key()) {
+                    record.links.push(link.key());
+                }
+            }
+        }
+
+        match String::from_utf8(data) {
+            Ok(content) if !heap::is_attachment(&path) => record.content = Some(content),
+            Ok(content) => record.data = Some(base64::encode(content)),
+            Err(e) => record.data = Some(base64::encode(e.into_bytes())),
+        }
+
+        // Through a Value, whose maps are sorted, so the output is stable
+        serde_json::to_writer(&mut *out, &serde_json::to_value(&record)?)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_hrefs() {
+        assert_eq!(href(Path::new("a.html"), Path::new("b c.html")), "b%20c.html");
+        assert_eq!(href(Path::new("x/y/a.html"), Path::new("tags/%23t.html")), "../../tags/%2523t.html");
+    }
+
+    #[test]
+    fn export_html() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("a.md"), "---\ntitle: Apples\ntags: fruit, docs\n---\nSee [[dir/b|bee]] and [[c]]")?;
+        heap.create_card(Some("dir/b.md"), "---\ntags: docs\n---\n# Bee <3\n\n*back* to [[a]]")?;
+        heap.create_card(Some("c.md"), "private")?;
+
+        let out = dir.path().join("site");
+        assert_eq!(html(&heap, &out, Some("docs"))?, 2);
+
+        let a = std::fs::read_to_string(out.join("a.html"))?;
+        assert!(a.contains("<title>Apples</title>"));
+        assert!(a.contains("<a href=\"dir/b.html\">bee</a> and c"));
+        assert!(a.contains("<a href=\"tags/fruit.html\">#fruit</a>"));
+        assert!(a.contains("<h2>Backlinks</h2>\n<ul>\n<li><a href=\"dir/b.html\">Bee &lt;3</a></li>"));
+
+        let b = std::fs::read_to_string(out.join("dir/b.html"))?;
+        assert!(b.contains("<em>back</em> to <a href=\"../a.html\">a</a>"));
+        assert!(b.contains("<a href=\"../index.html\">Index</a>"));
+        assert!(!out.join("c.html").exists());
+
+        let tag = std::fs::read_to_string(out.join("tags/docs.html"))?;
+        assert!(tag.contains("<li><a href=\"../a.html\">Apples</a></li>\n<li><a href=\"../dir/b.html\">"));
+
+        let index: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(out.join(SEARCH_INDEX))?)?;
+        assert_eq!(index[1]["url"], "dir/b.html");
+        assert_eq!(index[1]["tags"], serde_json::json!(["docs"]));
+
+        // Re-exporting replaces the previous export, but won't clobber other files
+        std::fs::write(out.join("stale.html"), "")?;
+        std::fs::write(heap.path().join("old.txt"), b"Written in the caf\xe9 in 1999")?;
+        heap.commit_paths(&["old.txt"])?;
+        html(&heap, &out, None)?;
+        assert!(!out.join("stale.html").exists());
+        assert!(out.join("c.html").exists());
+        assert!(std::fs::read_to_string(out.join("old.html"))?.contains("Written in the caf\u{e9}"));
+
+        // Raw HTML is escaped, cards differing by extension get a page each,
+        // and uncommitted edits aren't published
+        heap.create_card(Some("x.md"), "<script>alert(1)</script>\n\nhi <img src=x onerror=alert(2)>")?;
+        heap.create_card(Some("x.txt"), "plain")?;
+        std::fs::write(heap.path().join("c.md"), "draft")?;
+        html(&heap, &out, None)?;
+        let x = std::fs::read_to_string(out.join("x.md.html"))?;
+        assert!(x.contains("&lt;script&gt;") && x.contains("&lt;img") && !x.contains("<script>alert"), "{}", x);
+        assert!(std::fs::read_to_string(out.join("x.txt.html"))?.contains("plain"));
+        assert!(!out.join("x.html").exists());
+        assert!(std::fs::read_to_string(out.join("c.html"))?.contains("private"));
+        assert!(html(&heap, dir.path().join("heap").as_path(), None).is_err());
+
+        // Links and images only keep safe urls
+        heap.create_card(Some("urls.md"), concat!(
+            "[a](javascript:alert(1)) [b](JavaScript&#58;alert(2)) <javascript:alert(3)>\n\n",
+            "![c](data:image/svg+xml;base64,PHN2Zz4=) [d](https://example.com/x?y:z) ",
+            "[e](mailto:me@example.com) [f](dir/b.html#top) [g](page:1)"))?;
+        html(&heap, &out, None)?;
+        let urls = std::fs::read_to_string(out.join("urls.html"))?;
+        assert!(!urls.to_lowercase().contains("href=\"javascript") && !urls.contains("src=\"data:"), "{}", urls);
+        assert!(urls.contains("<a href=\"#\">a</a>") && urls.contains("<img src=\"#\" alt=\"c\""), "{}", urls);
+        assert!(urls.contains("href=\"https://example.com/x?y:z\"") && urls.contains("href=\"mailto:me@example.com\""));
+        assert!(urls.contains("href=\"dir/b.html#top\"") && urls.contains("<a href=\"#\">g</a>"), "{}", urls);
+
+        // Cards don't overwrite the index or tag pages
+        heap.create_card(Some("index.md"), "card called index")?;
+        heap.create_card(Some("tags/docs.md"), "card called docs")?;
+        html(&heap, &out, None)?;
+        assert!(std::fs::read_to_string(out.join("index.html"))?.contains("<input"));
+        assert!(std::fs::read_to_string(out.join("index.md.html"))?.contains("card called index"));
+        assert!(std::fs::read_to_string(out.join("tags/docs.html"))?.contains("<h1>#docs</h1>"));
+        assert!(std::fs::read_to_string(out.join("tags/docs.md.html"))?.contains("card called docs"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Front matter is an optional block of `key: value` attributes at the top of
+//! a card, fenced by `---` lines:
+//!
+//! ```text
+//! ---
+//! title: Groceries
+//! tags: food, errands
+//! ---
+//! eggs, milk
+//! ```
+
+const FENCE: &str = "---";
+const TAGS: &str = "tags";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FrontMatter {
+    attributes: Vec<(String, String)>,
+}
+
+impl FrontMatter {
+
+    /// Split `content` into its front matter, if any, and the body that follows
+    pub fn parse(content: &str) -> (Option<FrontMatter>, &str) {
+        let (block, body) = FrontMatter::split(content);
+        if block.is_empty() {
+            return (None, content);
+        }
+
+        let mut attributes = vec!();
+        // Everything between the fences
+        for line in block.lines().skip(1) {
+            if line.trim_end() == FENCE {
+                break;
+            }
+
+            match line.split_once(':') {
+                Some((key, value)) => attributes.push((key.trim().to_owned(), value.trim().to_owned())),
+                None if line.trim().is_empty() => {}
+                // Not something we wrote, leave the card alone
+                None => return (None, content),
+            }
+        }
+
+        (Some(FrontMatter { attributes }), body)
+    }
+
+    /// Split `content` after the closing fence of its front matter block,
+    /// whether or not the lines in it are attributes `parse` understands.
+    /// The block is empty if there is none.
+    pub fn split(content: &str) -> (&str, &str) {
+        let rest = match content.strip_prefix(FENCE) {
+            Some(rest) => rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n")),
+            None => None,
+        };
+
+        let rest = match rest {
+            Some(rest) => rest,
+            None => return ("", content),
+        };
+
+        let mut offset = content.len() - rest.len();
+        for line in rest.split_inclusive('\n') {
+            offset += line.len();
+            if line.trim_end() == FENCE {
+                return content.split_at(offset);
+            }
+        }
+
+        // No closing fence
+        ("", content)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.attributes.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => self.attributes.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let pos = self.attributes.iter().position(|(k, _)| k == key)?;
+        Some(self.attributes.remove(pos).1)
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Tags are stored as a comma separated list, optionally in brackets
+    pub fn tags(&self) -> Vec<String> {
+        self.get(TAGS)
+            .map(|tags| tags.trim_start_matches('[').trim_end_matches(']'))
+            .map(|tags| tags.split(',')
+                .map(|t| t.trim().trim_start_matches('#'))
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_owned())
+                .collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_tags<S: AsRef<str>>(&mut self, tags: &[S]) {
+        if tags.is_empty() {
+            self.remove(TAGS);
+        } else {
+            let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).collect();
+            self.set(TAGS, &tags.join(", "));
+        }
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        let mut tags = self.tags();
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_owned());
+            self.set_tags(&tags);
+        }
+    }
+
+    /// Render the front matter followed by `body`. Empty front matter is omitted.
+    pub fn render(&self, body: &str) -> String {
+        if self.is_empty() {
+            return body.to_owned();
+        }
+
+        let mut out = String::from(FENCE);
+        out.push('\n');
+        for (key, value) in &self.attributes {
+            out.push_str(key);
+            out.push_str(": ");
+            out.push_str(value);
+            out.push('\n');
+        }
+        out.push_str(FENCE);
+        out.push('\n');
+        out.push_str(body);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_render() {
+        let card = "---\ntitle: Groceries\ntags: [food, #errands]\n---\neggs\n";
+        let (fm, body) = FrontMatter::parse(card);
+        let mut fm = fm.unwrap();
+
+        assert_eq!(body, "eggs\n");
+        assert_eq!(fm.get("title"), Some("Groceries"));
+        assert_eq!(fm.tags(), vec!["food", "errands"]);
+
+        fm.add_tag("conflict");
+        assert_eq!(fm.render(body), "---\ntitle: Groceries\ntags: food, errands, conflict\n---\neggs\n");
+    }
+
+    #[test]
+    fn no_front_matter() {
+        assert_eq!(FrontMatter::parse("just a note"), (None, "just a note"));
+        assert_eq!(FrontMatter::parse("---\nunterminated: yes\n"), (None, "---\nunterminated: yes\n"));
+        assert_eq!(FrontMatter::parse("---\n\nnot an attribute\n---\n").0, None);
+    }
+
+    #[test]
+    fn split_unparsed() {
+        let card = "---\ntags:\n  - a\n---\nbody\n";
+        assert_eq!(FrontMatter::parse(card), (None, card));
+        assert_eq!(FrontMatter::split(card), ("---\ntags:\n  - a\n---\n", "body\n"));
+        assert_eq!(FrontMatter::split("---\nunterminated\n"), ("", "---\nunterminated\n"));
+    }
+}
@@ -1,25 +1,72 @@
 use anyhow::{Result, bail, Context};
 use std::process::Command; 
+use std::collections::BTreeSet;
 use std::path::{PathBuf, Path};
 use log::{debug, info};
 
-use toml;
-
 use crate::repo;
 use crate::index;
-use crate::card::Card;
+use crate::config::{self, CardFilter, Config};
+use crate::frontmatter::FrontMatter;
+use crate::merge;
+use crate::printer;
+use crate::links;
+use crate::pdf;
+use crate::saved;
+use crate::template;
+use crate::text::{self, Text};
+use crate::lock::{HeapLock, LockError};
 
-#[derive(Debug)]
+/// The last indexed commit. It is stored in the tantivy commit payload so it
+/// changes atomically with the index; the copy in the db is only a mirror,
+/// used to detect interrupted syncs and heaps indexed by older versions.
+#[derive(Debug, PartialEq)]
 struct HeapState {
     commit: Option<String>, // Last index commit
-    path: PathBuf 
+    mirror: Option<String>, // Copy of `commit` in the db
+}
+
+const COMMIT_KEY: &[u8] = b"commit";
+
+impl HeapState {
+    fn load(index: &index::Index, db: &sled::Db) -> Result<HeapState> {
+        let mirror = match db.get(COMMIT_KEY)? {
+            Some(ivec) => Some(std::str::from_utf8(ivec.as_ref())?.to_owned()),
+            None => None
+        };
+
+        Ok(HeapState {
+            commit: index.payload()?,
+            mirror
+        })
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.commit == self.mirror
+    }
+
+    /// Commit to replay changes from. Heaps indexed before the commit was
+    /// stored with the index only have the mirror.
+    fn indexed(&self) -> Option<&String> {
+        self.commit.as_ref().or(self.mirror.as_ref())
+    }
 }
 
 pub struct Heap {
     path: PathBuf,
-    db: sled::Db,
+    db: Option<sled::Db>, // None when opened read-only
     index: index::Index,
     repo: repo::Repo, 
+    lock: Option<HeapLock>, // Held for as long as the heap is open for writing
+    filter: CardFilter, // Which files are cards
+    worktree: Option<Worktree>, // Uncommitted cards searched as they are, if `[search] worktree` is set
+}
+
+/// Cards changed in the worktree but not committed, indexed in memory so
+/// searches see them as they are without anything being committed
+struct Worktree {
+    index: index::Index,
+    paths: BTreeSet<PathBuf>, // Every changed card, including removed ones
 }
 
 impl std::fmt::Debug for Heap {
@@ -27,12 +74,35 @@ impl std::fmt::Debug for Heap {
         f.debug_struct("Heap")
          .field("path", &self.path)
          .field("db", &self.db)
+         .field("lock", &self.lock)
          .finish()
     }
 }
 
 const NB_SUBDIR: &str = ".nb";
 
+/// Files attached to cards, stored by content under the heap root
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Attachments whose text is extracted and indexed
+const PDF_MIME: &str = "application/pdf";
+
+/// How long writers wait for another process to release the heap
+const DEFAULT_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Lock timeout in seconds, overriding DEFAULT_LOCK_TIMEOUT
+const LOCK_TIMEOUT_VAR: &str = "NB_LOCK_TIMEOUT";
+
+/// Searches don't wait for a writer; they search the index as it is instead
+const SEARCH_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(0);
+
+fn lock_timeout() -> std::time::Duration {
+    std::env::var(LOCK_TIMEOUT_VAR).ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(std::time::Duration::from_secs_f64)
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+}
+
 impl Heap {
     pub fn init<P: AsRef<Path>>(path: P) -> Result<Heap> {
         let path = path.as_ref().to_owned();
@@ -49,12 +119,15 @@ impl Heap {
         nb_path.push(NB_SUBDIR);
         std::fs::create_dir(&nb_path)?;
 
+        let lock = HeapLock::acquire(&nb_path, lock_timeout())?;
+
         let mut index_path = nb_path.clone();
         index_path.push("index");
 
         std::fs::create_dir(&index_path)?;
 
         let index = crate::Index::create(index_path)?;
+        write_schema_version(&nb_path)?;
 
         let mut db_path = nb_path.clone();
         db_path.push("db");
@@ -64,15 +137,26 @@ impl Heap {
             .create_new(true)
             .open()?;
 
+        let filter = card_filter(&path, &path)?;
+
         Ok(Heap {
             path,
-            db,
+            db: Some(db),
             index,
-            repo
+            repo,
+            lock: Some(lock),
+            filter,
+            worktree: None,
         })
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Heap> {
+        Heap::open_with_timeout(path, lock_timeout())
+    }
+
+    /// Open the heap for writing, waiting up to `timeout` for another
+    /// writer to finish
+    fn open_with_timeout<P: AsRef<Path>>(path: P, timeout: std::time::Duration) -> Result<Heap> {
         let path: PathBuf = path.as_ref().to_owned().canonicalize()?;
 
         let repo = crate::repo::Repo::open(&path)?;
@@ -86,89 +170,935 @@ impl Heap {
         let mut db_path = nb_path.clone();
         db_path.push("db");
 
+        let lock = HeapLock::acquire(&nb_path, timeout)?;
+
+        let version = read_schema_version(&nb_path)?;
+        if version != Some(index::SCHEMA_VERSION) {
+            eprintln!("Index schema has changed ({} -> {}), rebuilding the index",
+                version.map_or("unversioned".to_owned(), |v| v.to_string()),
+                index::SCHEMA_VERSION);
+            return Heap::rebuild(path, lock);
+        }
+
         let index = crate::Index::open(index_path)?;
         let db = sled::open(db_path)?;
 
+        let filter = card_filter(&path, &path)?;
+
+        let mut heap = Heap {
+            path,
+            db: Some(db),
+            index,
+            repo,
+            lock: Some(lock),
+            filter,
+            worktree: None,
+        };
+
+        heap.recover()?;
+
+        Ok(heap)
+    }
+
+    /// Open the heap for searching only. Takes no lock and no index writer,
+    /// so it never waits on, or blocks, other nb processes.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Heap> {
+        let path: PathBuf = path.as_ref().to_owned().canonicalize()?;
+
+        let repo = crate::repo::Repo::open(&path)?;
+
+        let nb_path = path.join(NB_SUBDIR);
+
+        if read_schema_version(&nb_path)? != Some(index::SCHEMA_VERSION) {
+            // The index needs rebuilding, which needs a writer
+            return Heap::open(path);
+        }
+
+        let index = crate::Index::open_read_only(nb_path.join("index"))?;
+        let filter = card_filter(&path, &path)?;
+
         Ok(Heap {
             path,
-            db,
+            db: None,
             index,
-            repo
+            repo,
+            lock: None,
+            filter,
+            worktree: None,
         })
     }
 
-    pub fn sync(&mut self) -> Result<()> {
+    /// Open the heap for searching. If `sync` is set and the index isn't
+    /// current the index is brought up to date first, otherwise no writer
+    /// is taken at all. If another process is writing, the index is
+    /// searched as it is rather than waiting. With `sync` and `[search]
+    /// worktree` set, uncommitted changes to cards are searched too.
+    pub fn open_for_search<P: AsRef<Path>>(path: P, sync: bool) -> Result<Heap> {
+        let mut heap = Heap::open_read_only(&path)?;
+        if !sync {
+            return Ok(heap);
+        }
+
+        if !heap.is_current()? {
+            drop(heap);
+            heap = match Heap::open_with_timeout(&path, SEARCH_LOCK_TIMEOUT) {
+                Ok(mut heap) => {
+                    heap.sync()?;
+                    heap
+                }
+                Err(e) if e.downcast_ref::<LockError>().is_some() => {
+                    log::warn!("{}, searching without syncing", e);
+                    Heap::open_read_only(&path)?
+                }
+                Err(e) => return Err(e)
+            };
+        }
+
+        heap.search_worktree()?;
+        Ok(heap)
+    }
+
+    /// Whether the index is up to date with HEAD. Only reads the index
+    /// metadata and HEAD, so it's cheap enough to check on every search.
+    pub fn is_current(&self) -> Result<bool> {
+        let head = self.repo.head()?.id().to_string();
+        Ok(self.index.payload()?.as_ref() == Some(&head) && self.is_filter_current()?)
+    }
 
-        let latest_commit = match self.db.get(b"commit")? {
-            Some(ivec) => {
-                Some(std::str::from_utf8(ivec.as_ref())?.to_owned())
+    /// If `[search] worktree` is set, have searches see the cards changed in
+    /// the worktree as they are rather than as committed. They're indexed
+    /// in memory, so nothing is committed and no writer is needed.
+    fn search_worktree(&mut self) -> Result<()> {
+        if !self.config()?.search.worktree {
+            return Ok(());
+        }
+
+        let paths: BTreeSet<PathBuf> = self.worktree_changes()?.into_iter().collect();
+        let mut index = index::Index::create_in_ram()?;
+        for path in &paths {
+            let full_path = self.path.join(path);
+            if !full_path.is_file() {
+                continue;
             }
-            _ => None
-        };
+            if let Some(note) = card_note(&index, path, &std::fs::read(&full_path)?) {
+                index.add(path, note);
+            }
+        }
+        index.commit(None)?;
+        index.reload()?;
 
-        let head = self.repo.head()?;
-        let diffs = self.repo.diff(latest_commit.as_ref(), None)?;
-    
-        for diff in diffs {
-            match diff {
-                (git2::Delta::Added, path) | (git2::Delta::Modified, path) => { 
-                    self.index.delete(&path);
-                    println!("{:?}", path);
+        self.worktree = Some(Worktree { index, paths });
+        Ok(())
+    }
+
+    /// Whether the index was built with the card filter the config and
+    /// ignore files give now
+    fn is_filter_current(&self) -> Result<bool> {
+        let indexed = read_filter_fingerprint(&self.path.join(NB_SUBDIR))?;
+        Ok(indexed.as_deref() == Some(card_filter(&self.path, &self.path)?.fingerprint()))
+    }
+
+    /// Cards added, changed or removed in the worktree but not committed
+    fn worktree_changes(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.repo.changed_files()?.into_iter()
+            .filter(|path| self.is_card(path))
+            .collect())
+    }
 
-                    let mut note = self.index.notebuilder(&path);
+    fn db(&self) -> Result<&sled::Db> {
+        self.db.as_ref().context("heap is open read-only")
+    }
+
+    /// Replay any changes lost by a sync that was interrupted between
+    /// committing the index and recording the commit in the db.
+    fn recover(&mut self) -> Result<()> {
+        let state = HeapState::load(&self.index, self.db()?)?;
+
+        if !state.is_consistent() {
+            log::warn!("index ({:?}) and db ({:?}) disagree on the last indexed commit, resyncing",
+                state.commit, state.mirror);
+            self.sync()?;
+        }
+
+        Ok(())
+    }
 
-                    let content = std::fs::read_to_string(&self.path.join(&note.path))?;
-                    note.body(&content);
+    pub fn sync(&mut self) -> Result<()> {
+        let state = HeapState::load(&self.index, self.db()?)?;
+        // Pick up any changes to the config or ignore files
+        self.filter = card_filter(&self.path, &self.path)?;
+
+        let mut latest_commit = state.indexed().cloned();
 
-                    //let doc = doc_builder.document();
+        if let Some(commit) = &latest_commit {
+            if self.repo.resolve(commit).is_err() {
+                log::warn!("last indexed commit {} no longer exists, rebuilding index", commit);
+                self.index.clear()?;
+                latest_commit = None;
+            }
+        }
 
-                    self.index.add(&path, note);
+        // Files that aren't in the diff may have become cards, or stopped
+        // being ones
+        let nb_path = self.path.join(NB_SUBDIR);
+        if latest_commit.is_some() && !self.is_filter_current()? {
+            eprintln!("Which files are cards has changed, rebuilding the index");
+            self.index.clear()?;
+            latest_commit = None;
+        }
+
+        let head_id = self.repo.head()?.id().to_string();
+        let diffs = self.repo.diff(latest_commit.as_ref(), None)?;
+        let total = diffs.len();
+        let mut skipped = vec!();
+
+        for (done, diff) in diffs.into_iter().enumerate() {
+            match diff {
+                (git2::Delta::Added | git2::Delta::Modified | git2::Delta::Copied | git2::Delta::Typechange, path)
+                    if !self.is_indexable(&path) => {
+                    self.index.delete(&path)
+                }
+                (git2::Delta::Added | git2::Delta::Modified | git2::Delta::Copied | git2::Delta::Typechange, path) => {
+                    if !self.index_path(&path)? {
+                        skipped.push(path);
+                    }
                 }
                 (git2::Delta::Deleted, path) => { self.index.delete(&path) } 
-                (git2::Delta::Renamed, _path) => { todo!("Handling Renaming. Need both old and new path") } 
-                _ => todo!()
+                // Renames come as a deletion and an addition; the rest
+                // don't occur between two commits
+                (status, path) => log::debug!("not indexing {} ({:?})", path.display(), status),
             }
+            printer::progress("indexing", done + 1, total);
         } 
+        printer::skipped(&skipped);
 
-        self.index.commit()?;
+        self.index.commit(Some(&head_id))?;
         self.index.reload()?;
+        write_filter_fingerprint(&nb_path, self.filter.fingerprint())?;
+
+        self.db()?.insert(COMMIT_KEY, head_id.into_bytes())?;
+        self.db()?.flush()?;
+
+        Ok(())
+    }
+
+    pub fn add_remote(&self, name: &str, url: &str) -> Result<()> {
+        self.repo.add_remote(name, url)
+    }
+
+    /// Fetch and merge from `remote`, then index whatever changed. Returns
+    /// the paths of cards left with conflicts, whose versions were both kept.
+    pub fn pull(&mut self, remote: &str) -> Result<Vec<PathBuf>> {
+        self.repo.fetch(remote)?;
+        let merge = self.repo.merge(remote, merge::resolve)?;
+        info!("pull from {}: {:?}", remote, merge);
+
+        self.sync()?;
+
+        match merge {
+            repo::Merge::Merged { conflicts, .. } => Ok(conflicts),
+            _ => Ok(vec!()),
+        }
+    }
+
+    pub fn push(&mut self, remote: &str) -> Result<()> {
+        self.repo.push(remote)
+    }
+
+    /// Pull then push, leaving the local heap and `remote` in step. Returns
+    /// the paths of cards the pull left with conflicts.
+    pub fn sync_remote(&mut self, remote: &str) -> Result<Vec<PathBuf>> {
+        let conflicts = self.pull(remote)?;
+        self.push(remote)?;
+        Ok(conflicts)
+    }
+
+    /// Whether `path`, relative to the heap root, is a card or an attachment.
+    /// Everything else in the heap is left out of the index.
+    pub fn is_indexable(&self, path: &Path) -> bool {
+        is_attachment(path) || self.is_card(path)
+    }
+
+    fn is_card(&self, path: &Path) -> bool {
+        !is_attachment(path) && !template::is_template(path) && self.filter.is_card(path)
+    }
+
+    /// Settings from `.nb/config.toml`, read afresh
+    pub fn config(&self) -> Result<Config> {
+        Config::open(self.path.join(NB_SUBDIR).join(config::CONFIG_FILE))
+    }
+
+    /// Which files under `root` would be cards in this heap, going by its
+    /// config and the ignore files at `root`
+    pub fn card_filter(&self, root: &Path) -> Result<CardFilter> {
+        card_filter(&self.path, root)
+    }
+
+    /// (Re)index the card or attachment at `path`, relative to the heap root.
+    /// Returns false if it was skipped as binary.
+    fn index_path(&mut self, path: &Path) -> Result<bool> {
+        if is_attachment(path) {
+            self.index_attachment(path)?;
+            Ok(true)
+        } else {
+            self.index_card(path)
+        }
+    }
+
+    fn index_card(&mut self, path: &Path) -> Result<bool> {
+        self.index.delete(path);
+
+        // As committed, which edits in the worktree may not be yet
+        let data = self.repo.read_head(path)?;
+        match card_note(&self.index, path, &data) {
+            Some(note) => {
+                self.index.add(path, note);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-        self.db.insert(b"commit", head.id().to_string().into_bytes())?;
+    /// Index an attachment by its name and type. Its contents aren't text,
+    /// so it has no body.
+    fn index_attachment(&mut self, path: &Path) -> Result<()> {
+        self.index.delete(path);
 
+        let data = self.repo.read_head(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        let mime = mime_type(path, &data);
+        let mut note = self.index.notebuilder(path);
+        note.attachment(&name, mime);
+
+        if mime == PDF_MIME {
+            match pdf::text(&data) {
+                Ok(text) => { note.attachment_text(&text); }
+                Err(e) => log::warn!("Failed to extract text from {}: {}", path.display(), e),
+            }
+        }
+
+        self.index.add(path, note);
         Ok(())
     }
 
-    /// TODO: Fix this 
+    /// Rebuild the index from HEAD from scratch. Works on heaps whose index
+    /// is missing, corrupt or built with an old schema.
+    pub fn reindex<P: AsRef<Path>>(path: P) -> Result<Heap> {
+        let path: PathBuf = path.as_ref().to_owned().canonicalize()?;
+        let lock = HeapLock::acquire(path.join(NB_SUBDIR), lock_timeout())?;
+        Heap::rebuild(path, lock)
+    }
+
+    fn rebuild(path: PathBuf, lock: HeapLock) -> Result<Heap> {
+        let repo = crate::repo::Repo::open(&path)?;
+
+        let nb_path = path.join(NB_SUBDIR);
+        let index_path = nb_path.join("index");
+
+        if index_path.exists() {
+            std::fs::remove_dir_all(&index_path)
+                .with_context(|| format!("Failed to remove {}", index_path.display()))?;
+        }
+        std::fs::create_dir_all(&index_path)?;
+
+        let index = crate::Index::create(index_path)?;
+        write_schema_version(&nb_path)?;
+        let db = sled::open(nb_path.join("db"))?;
+        db.remove(COMMIT_KEY)?;
+
+        let filter = card_filter(&path, &path)?;
+
+        let mut heap = Heap {
+            path,
+            db: Some(db),
+            index,
+            repo,
+            lock: Some(lock),
+            filter,
+            worktree: None,
+        };
+
+        heap.sync()?;
+
+        Ok(heap)
+    }
+
+    /// Compare the cards at HEAD with the indexed documents and check the
+    /// db is readable. With `fix`, reindex or remove any bad documents.
+    pub fn fsck(&mut self, fix: bool) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        let db = self.db()?;
+        for tree in db.tree_names() {
+            for item in db.open_tree(tree)?.iter() {
+                item.context("Failed to read db")?;
+                report.db_entries += 1;
+            }
+        }
+
+        let mut indexed: std::collections::HashMap<PathBuf, Vec<String>> = std::collections::HashMap::new();
+        for (path, body) in self.index.documents()? {
+            indexed.entry(path).or_default().push(body);
+        }
+
+        // Files no longer picked as cards are left indexed, so show as orphaned
+        let files: Vec<PathBuf> = self.repo.list_files()?.into_iter()
+            .filter(|path| self.is_indexable(path))
+            .collect();
+        let total = files.len();
+
+        for (done, path) in files.iter().enumerate() {
+            match indexed.remove(path) {
+                // Binary files are skipped by sync
+                None if !is_attachment(path) && text::decode(&self.repo.read_head(path)?) == Text::Binary => {}
+                None => report.missing.push(path.clone()),
+                Some(bodies) if bodies.len() > 1 => report.duplicated.push(path.clone()),
+                Some(_) if is_attachment(path) => {}
+                Some(bodies) => {
+                    let content = text::decode(&self.repo.read_head(path)?).into_string();
+                    if content.as_ref() != Some(&bodies[0]) {
+                        report.stale.push(path.clone());
+                    }
+                }
+            }
+            printer::progress("checking", done + 1, total);
+        }
+
+        report.orphaned = indexed.into_keys().collect();
+        report.orphaned.sort();
+
+        if fix && !report.is_clean() {
+            for path in &report.orphaned {
+                self.index.delete(path);
+            }
+            let reindex: Vec<PathBuf> = report.missing.iter()
+                .chain(&report.stale)
+                .chain(&report.duplicated)
+                .cloned()
+                .collect();
+            for path in reindex {
+                self.index_path(&path)?;
+            }
+
+            // Leave the recorded commit untouched
+            let commit = self.index.payload()?;
+            self.index.commit(commit.as_deref())?;
+            self.index.reload()?;
+        }
+
+        Ok(report)
+    }
+
+    /// Cards matching `query`, which may name saved queries as
+    /// `saved:<name>`. Attachments matching by name, type or the text
+    /// extracted from them are listed as the cards they're attached to,
+    /// with `attachment` set.
     pub fn find(&self, query: &str) -> anyhow::Result<Vec<index::QueryResult>> {
-        let result = self.index.query(query)?;
+        let query = if saved::is_used(query) {
+            saved::expand(query, &self.queries()?)?
+        } else {
+            query.to_owned()
+        };
+        let mut result = self.index.query(&query)?;
+
+        // Cards changed in the worktree are found as they are now instead
+        if let Some(worktree) = &self.worktree {
+            result.retain(|hit| !worktree.paths.contains(Path::new(&hit.card.path)));
+            let mut changed = worktree.index.query(&query)?;
+            changed.append(&mut result);
+            result = changed;
+        }
         debug!("query_result: {:?}", result);
-        //for doc in result {
-        //    let res = QueryResult("wat");
-        //}
-        return Ok(result)
+
+        let mut found: Vec<index::QueryResult> = vec!();
+        for hit in result {
+            let cards = if is_attachment(Path::new(&hit.card.path)) {
+                self.backlinks(&hit.card.path)?
+            } else {
+                vec!()
+            };
+
+            // Attachments nothing links to any more are listed as they are
+            if cards.is_empty() {
+                if !found.iter().any(|r| r.card.path == hit.card.path) {
+                    found.push(hit);
+                }
+                continue;
+            }
+
+            for card in cards {
+                if !found.iter().any(|r| r.card.path == card.card.path) {
+                    found.push(index::QueryResult {
+                        card: card.card,
+                        snippet: hit.snippet.clone(),
+                        attachment: Some(hit.card.path.clone()),
+                    });
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Saved queries by name
+    pub fn queries(&self) -> Result<saved::Queries> {
+        saved::load(&self.path)
+    }
+
+    /// Save `query` as `name`, replacing any query saved as `name` before,
+    /// and commit it
+    pub fn save_query(&mut self, name: &str, query: &str) -> Result<()> {
+        saved::check_name(name)?;
+        let mut queries = self.queries()?;
+        queries.insert(name.to_owned(), query.to_owned());
+
+        // Check it parses, and refers to no missing or circular saved queries
+        self.index.query(&saved::expand(query, &queries)?)?;
+
+        saved::store(&self.path, &queries)?;
+        self.commit_paths(&[saved::QUERIES_FILE])
+    }
+
+    /// Cards tagged as conflicted by a merge
+    pub fn conflicts(&self) -> Result<Vec<index::QueryResult>> {
+        self.find(&format!("tags:{}", merge::CONFLICT_TAG))
+    }
+
+    /// Write a new card, defaulting to a ULID path, commit and index it.
+    /// Returns its path relative to the heap.
+    pub fn create_card<P: AsRef<Path>>(&mut self, path: Option<P>, content: &str) -> Result<PathBuf> {
+        let path = self.write_card(path, content)?;
+        self.commit_paths(&[&path])?;
+        Ok(path)
+    }
+
+    /// Write a new card without committing it, for callers that commit many
+    /// cards at once with `commit_paths`. Returns its path relative to the heap.
+    pub fn write_card<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: Option<P>, content: C) -> Result<PathBuf> {
+        let path = self.new_card_path(path)?;
+        let full_path = self.path.join(&path);
+
+        if let Some(dir) = full_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&full_path, content)?;
+        Ok(path)
+    }
+
+    /// Open a new card in the editor, then commit and index it
+    pub fn add_card<P: AsRef<Path>>(&mut self, path: Option<P>) -> Result<PathBuf> {
+        self.add_card_from(path, "")
+    }
+
+    /// Open a new card starting out as `content` in the editor, then commit
+    /// and index it. A card left as it started isn't kept.
+    pub fn add_card_from<P: AsRef<Path>>(&mut self, path: Option<P>, content: &str) -> Result<PathBuf> {
+        let path = self.edit_new_card(path, content)?;
+        self.commit_paths(&[&path])?;
+        Ok(path)
+    }
+
+    /// `add_card_from` without the commit, for callers committing through
+    /// the process that holds the heap
+    pub fn edit_new_card<P: AsRef<Path>>(&self, path: Option<P>, content: &str) -> Result<PathBuf> {
+        let path = self.write_card(path, content)?;
+        let full_path = self.path.join(&path);
+        if let Err(e) = launch_editor(&full_path) {
+            let _ = std::fs::remove_file(&full_path);
+            return Err(e);
+        }
+
+        if !full_path.exists() || std::fs::read_to_string(&full_path).unwrap_or_default() == content {
+            let _ = std::fs::remove_file(&full_path);
+            bail!("Nothing written to {}, card not added", path.display());
+        }
+
+        Ok(path)
+    }
+
+    /// Validate a path for a new card, or generate one
+    fn new_card_path<P: AsRef<Path>>(&self, path: Option<P>) -> Result<PathBuf> {
+        let path = match path {
+            Some(path) => path.as_ref().to_owned(),
+            None => PathBuf::from(format!("{}.md", rusty_ulid::generate_ulid_string())),
+        };
+
+        check_card_path(&path)?;
+        if self.path.join(&path).exists() {
+            bail!("Card already exists: {}", path.display());
+        }
+
+        Ok(path)
+    }
+
+    /// Store `data` as an attachment named `name`, without committing it.
+    /// Attachments are stored by content, so storing the same data twice
+    /// gives the same path. Returns its path relative to the heap.
+    pub fn write_attachment(&self, name: &str, data: &[u8]) -> Result<PathBuf> {
+        let path = attachment_path(name, data)?;
+        let full_path = self.path.join(&path);
+        if !full_path.exists() {
+            std::fs::create_dir_all(full_path.parent().unwrap())?;
+            std::fs::write(&full_path, data)?;
+        }
+
+        Ok(path)
+    }
+
+    /// Attach the file at `file` to the card at `path`: store it, link it
+    /// at the end of the card and commit both. Images are embedded.
+    /// Returns the attachment's path relative to the heap.
+    pub fn attach<P: AsRef<Path>, F: AsRef<Path>>(&mut self, path: P, file: F) -> Result<PathBuf> {
+        let (path, file) = (path.as_ref(), file.as_ref());
+        let mut content = self.read_card(path)?;
+
+        let data = std::fs::read(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let attachment = self.write_attachment(&name, &data)?;
+
+        // Link relative to the card, so it resolves from its directory
+        let depth = path.components().count() - 1;
+        let url = format!("{}{}", "../".repeat(depth), attachment.display());
+        let embed = if mime_type(&attachment, &data).starts_with("image/") { "!" } else { "" };
+
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&format!("\n{}[{}](<{}>)\n", embed, name, url));
+        std::fs::write(self.path.join(path), content)?;
+
+        self.commit_paths(&[path, attachment.as_path()])?;
+        Ok(attachment)
+    }
+
+    /// Current contents of the card at `path`, decoded as sync decodes it
+    /// if it isn't UTF-8
+    pub fn read_card<P: AsRef<Path>>(&self, path: P) -> Result<String> {
+        let path = path.as_ref();
+        check_card_path(path)?;
+        let data = std::fs::read(self.path.join(path))
+            .with_context(|| format!("Failed to read card {}", path.display()))?;
+        match text::decode(&data).into_string() {
+            Some(content) => Ok(content),
+            None => bail!("Not a text card: {}", path.display()),
+        }
+    }
+
+    /// Replace the contents of an existing card, commit and index it
+    pub fn update_card<P: AsRef<Path>>(&mut self, path: P, content: &str) -> Result<()> {
+        let path = path.as_ref();
+        check_card_path(path)?;
+        let full_path = self.path.join(path);
+        if !full_path.is_file() {
+            bail!("No such card: {}", path.display());
+        }
+
+        std::fs::write(&full_path, content)?;
+        self.commit_paths(&[path])
     }
 
-    pub fn add_card<P: AsRef<Path>>(&mut self, path: Option<P>) -> Result<()> {
+    /// Add `text` as lines at the end of an existing card, then commit and
+    /// index it
+    pub fn append_card<P: AsRef<Path>>(&mut self, path: P, text: &str) -> Result<()> {
+        let mut content = self.read_card(&path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&lines(text));
+        self.update_card(path, &content)
+    }
 
-        if let Some(_path) = path {
+    /// Add `text` as lines at the start of an existing card's body, below
+    /// its front matter, then commit and index it
+    pub fn prepend_card<P: AsRef<Path>>(&mut self, path: P, text: &str) -> Result<()> {
+        let content = self.read_card(&path)?;
+        // The front matter is kept exactly as it was written, even where
+        // it's YAML that FrontMatter can't parse
+        let (frontmatter, body) = FrontMatter::split(&content);
+        let content = format!("{}{}{}", frontmatter, lines(text), body);
+        self.update_card(path, &content)
+    }
 
+    /// Remove a card, commit the removal and drop it from the index
+    pub fn delete_card<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        check_card_path(path)?;
+        let full_path = self.path.join(path);
+        if !full_path.is_file() {
+            bail!("No such card: {}", path.display());
         }
-        unimplemented!() 
+
+        std::fs::remove_file(&full_path)?;
+        self.commit_paths(&[path])
+    }
+
+    /// Links out of the card at `path`, resolved to cards where they exist
+    pub fn links<P: AsRef<Path>>(&self, path: P) -> Result<Vec<ResolvedLink>> {
+        let content = self.read_card(path)?;
+        Ok(links::extract(&content).into_iter()
+            .map(|link| {
+                let path = self.resolve_link(&link.key());
+                ResolvedLink { link, path }
+            })
+            .collect())
+    }
+
+    /// Path of the card a link key refers to, if it exists
+    pub fn resolve_link(&self, key: &str) -> Option<PathBuf> {
+        let candidates = [format!("{}.md", key), key.to_owned()];
+        candidates.iter()
+            .map(PathBuf::from)
+            .find(|path| self.path.join(path).is_file())
+    }
+
+    /// Cards linking to the card at `path`
+    pub fn backlinks<P: AsRef<Path>>(&self, path: P) -> Result<Vec<index::QueryResult>> {
+        self.index.query_term("links", &links::key(path))
+    }
+
+    /// Paths of every committed file, cards and attachments alike
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        self.repo.list_files()
+    }
+
+    /// Contents of `path` as of the last commit
+    pub fn read_committed<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        self.repo.read_head(path)
+    }
+
+    /// When each file was added and last changed, in seconds since the epoch
+    pub fn history(&self) -> Result<std::collections::HashMap<PathBuf, (i64, i64)>> {
+        self.repo.history()
+    }
+
+    /// Paths of every indexed card
+    pub fn cards(&self) -> Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = self.index.documents()?.into_iter()
+            .map(|(path, _)| path)
+            .filter(|path| self.is_card(path))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Every tag in use, with the number of cards tagged with it
+    pub fn tags(&self) -> Result<std::collections::BTreeMap<String, usize>> {
+        self.index.tags()
     }
     
+    /// Open a card in the editor, then commit and index it
     pub fn edit_card<P: AsRef<Path> + Copy>(&mut self, path: P) -> Result<()> {
-        let mut child = Command::new("vim")
-            .args(&[self.path.join(path)])
-            .spawn()
-            .expect("failed to launch editor");
+        check_card_path(path.as_ref())?;
+        launch_editor(&self.path.join(path))?;
+        self.commit_card(path)
+    }
+
+    /// Commit and index the card at `path` after it was changed outside nb,
+    /// such as in an editor
+    pub fn commit_card<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        check_card_path(path)?;
+        self.commit_paths(&[path])
+    }
+
+    /// Commit `paths`, relative to the heap, and index the result
+    pub fn commit_paths<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<()> {
+        if !paths.is_empty() {
+            self.repo.commit_paths(paths)?;
+        }
+        self.sync()
+    }
+
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.repo.is_ignored(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// What's indexed for the card at `path` holding `data`, or None if it
+/// looks binary
+fn card_note(index: &index::Index, path: &Path, data: &[u8]) -> Option<index::Note> {
+    let content = match text::decode(data) {
+        Text::Utf8(content) => content,
+        Text::Decoded(content, encoding) => {
+            log::warn!("{} isn't UTF-8, read it as {}", path.display(), encoding);
+            content
+        }
+        Text::Binary => {
+            log::warn!("{} looks binary, not indexing it", path.display());
+            return None;
+        }
+    };
+
+    let mut note = index.notebuilder(path);
+    note.body(&content);
+
+    if let (Some(fm), _) = FrontMatter::parse(&content) {
+        for tag in fm.tags() {
+            note.tag(&tag);
+        }
+    }
+
+    for link in links::extract(&content) {
+        note.link(&link.key());
+    }
+
+    // Attachments are found by the cards they're attached to
+    for attachment in links::attachments(&content) {
+        note.link(&links::key(attachment));
+    }
+
+    Some(note)
+}
+
+/// `text` ending in a newline, to add to a card as whole lines
+fn lines(text: &str) -> String {
+    if text.ends_with('\n') {
+        text.to_owned()
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+/// Card filter for files under `root` from the config of the heap at `heap`
+fn card_filter(heap: &Path, root: &Path) -> Result<CardFilter> {
+    let config = Config::open(heap.join(NB_SUBDIR).join(config::CONFIG_FILE))?;
+    CardFilter::new(&config.cards, root)
+}
+
+/// Path, relative to the heap, that `write_attachment` stores `data` at
+pub fn attachment_path(name: &str, data: &[u8]) -> Result<PathBuf> {
+    let id = git2::Oid::hash_object(git2::ObjectType::Blob, data)?;
+    let name = Path::new(name).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or_else(|| "attachment".to_owned());
+
+    Ok(Path::new(ATTACHMENTS_DIR).join(id.to_string()).join(name))
+}
+
+/// Whether `path`, relative to the heap, is an attachment rather than a card
+pub fn is_attachment(path: &Path) -> bool {
+    path.starts_with(ATTACHMENTS_DIR)
+}
+
+/// MIME type of an attachment, sniffed from its data or else guessed from
+/// its extension
+fn mime_type(path: &Path, data: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(data) {
+        return kind.mime_type();
+    }
+
+    match path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("json") => "application/json",
+        Some("html") | Some("htm") => "text/html",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Card paths are relative, stay inside the heap and keep out of hidden
+/// files and directories, such as `.git` and `.nb`. Checked before the
+/// path is used at all.
+pub fn check_card_path(path: &Path) -> Result<()> {
+    let mut components = path.components().peekable();
+    if components.peek().is_none() {
+        bail!("Empty card path");
+    }
+    for component in components {
+        match component {
+            std::path::Component::Normal(name) if !name.to_string_lossy().starts_with('.') => {}
+            std::path::Component::Normal(_) => bail!("Card paths can't be hidden files: {}", path.display()),
+            _ => bail!("Card paths must be relative to the heap: {}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+pub fn launch_editor(path: &Path) -> Result<()> {
+    let mut child = Command::new("vim")
+        .args([path])
+        .spawn()
+        .context("failed to launch editor")?;
+
+    let exit = child.wait().context("failed to wait on editor subprocess")?;
+    if !exit.success() {
+        bail!("Editor exited with {}", exit);
+    }
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ResolvedLink {
+    #[serde(flatten)]
+    pub link: links::Link,
+    pub path: Option<PathBuf>, // None for broken links
+}
+
+const SCHEMA_VERSION_FILE: &str = "schema_version";
+
+/// Schema version the heap's index was built with. None for heaps created
+/// before the version was recorded.
+fn read_schema_version(nb_path: &Path) -> Result<Option<u32>> {
+    let path = nb_path.join(SCHEMA_VERSION_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let version = std::fs::read_to_string(&path)?;
+    let version = version.trim().parse()
+        .with_context(|| format!("Invalid schema version in {}", path.display()))?;
+    Ok(Some(version))
+}
+
+fn write_schema_version(nb_path: &Path) -> Result<()> {
+    std::fs::write(nb_path.join(SCHEMA_VERSION_FILE), index::SCHEMA_VERSION.to_string())
+        .context("Failed to record schema version")
+}
+
+const FILTER_FINGERPRINT_FILE: &str = "filter_fingerprint";
 
-        let _exit = child.wait().context("failed to wait on editor subprocess")?;
-        
-        self.repo.commit_paths(&[path])
+/// Fingerprint of the card filter the index was built with. None for
+/// heaps indexed before it was recorded.
+fn read_filter_fingerprint(nb_path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(nb_path.join(FILTER_FINGERPRINT_FILE)) {
+        Ok(fingerprint) => Ok(Some(fingerprint.trim().to_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read card filter fingerprint"),
     }
 }
 
-pub struct SearchResult(PathBuf, String);
+fn write_filter_fingerprint(nb_path: &Path, fingerprint: &str) -> Result<()> {
+    std::fs::write(nb_path.join(FILTER_FINGERPRINT_FILE), fingerprint)
+        .context("Failed to record card filter fingerprint")
+}
+
+/// Differences between the cards at HEAD and the index
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct FsckReport {
+    pub missing: Vec<PathBuf>,    // At HEAD but not indexed
+    pub stale: Vec<PathBuf>,      // Indexed content differs from HEAD
+    pub duplicated: Vec<PathBuf>, // Indexed more than once
+    pub orphaned: Vec<PathBuf>,   // Indexed but not at HEAD, or no longer a card
+    pub db_entries: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty()
+            && self.stale.is_empty()
+            && self.duplicated.is_empty()
+            && self.orphaned.is_empty()
+    }
+}
 
 //impl HeapState {
 //    fn open<P: AsRef<Path>>(path: P) -> Result<State> {
@@ -205,6 +1135,7 @@ pub struct SearchResult(PathBuf, String);
 //    pub state: State,
 //}
 
+#[cfg(test)]
 mod test {
     #[allow(unused_imports)]
     use super::*;
@@ -228,4 +1159,444 @@ mod test {
         println!("Heap: {:?}", heap_opened);
         //assert!(false);
     }
+
+    fn write_card(heap: &mut Heap, path: &str, content: &str) -> Result<()> {
+        std::fs::write(heap.path.join(path), content)?;
+        heap.repo.commit_paths(&[path])?;
+        heap.sync()
+    }
+
+    #[test]
+    fn test_recover_interrupted_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+
+        let mut heap = Heap::init(&path)?;
+        heap.sync()?;
+
+        // Simulate a sync that recorded the commit but never committed the index
+        std::fs::write(path.join("lost.md"), "lost update")?;
+        heap.repo.commit_paths(&["lost.md"])?;
+        let head = heap.repo.head()?.id().to_string();
+        heap.db()?.insert(COMMIT_KEY, head.as_bytes())?;
+        assert!(!HeapState::load(&heap.index, heap.db()?)?.is_consistent());
+        drop(heap);
+
+        let heap = Heap::open(&path)?;
+        let state = HeapState::load(&heap.index, heap.db()?)?;
+        assert!(state.is_consistent());
+        assert_eq!(state.commit, Some(head));
+        assert_eq!(heap.find("lost")?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reindex_and_fsck() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+
+        let mut heap = Heap::init(&path)?;
+        write_card(&mut heap, "a.md", "apples")?;
+        write_card(&mut heap, "b.md", "bananas")?;
+        assert!(heap.fsck(false)?.is_clean());
+
+        // Break the index behind the heap's back
+        heap.index.delete(Path::new("a.md"));
+        let orphan = heap.index.notebuilder(Path::new("gone.md"));
+        heap.index.add(Path::new("gone.md"), orphan);
+        heap.index.commit(None)?;
+        heap.index.reload()?;
+
+        // Fixed from HEAD, like it's checked, not from uncommitted edits
+        std::fs::write(path.join("a.md"), "avocados")?;
+        let report = heap.fsck(true)?;
+        assert_eq!(report.missing, vec![PathBuf::from("a.md")]);
+        assert_eq!(report.orphaned, vec![PathBuf::from("gone.md")]);
+        assert!(heap.fsck(false)?.is_clean());
+        assert_eq!(heap.find("apples")?.len(), 1);
+        assert!(heap.find("avocados")?.is_empty());
+        drop(heap);
+
+        std::fs::remove_dir_all(path.join(NB_SUBDIR).join("index"))?;
+        let heap = Heap::reindex(&path)?;
+        assert_eq!(heap.find("apples OR bananas")?.len(), 2);
+        drop(heap);
+        assert!(Heap::open(&path)?.fsck(false)?.is_clean());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_upgrade() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+
+        let mut heap = Heap::init(&path)?;
+        write_card(&mut heap, "a.md", "apples")?;
+        drop(heap);
+
+        // Pretend the heap was built by an older version
+        let nb_path = path.join(NB_SUBDIR);
+        std::fs::remove_file(nb_path.join(SCHEMA_VERSION_FILE))?;
+
+        let heap = Heap::open(&path)?;
+        assert_eq!(read_schema_version(&nb_path)?, Some(index::SCHEMA_VERSION));
+        assert_eq!(heap.find("apples")?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_open() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+
+        let mut writer = Heap::init(&path)?;
+        write_card(&mut writer, "a.md", "apples")?;
+
+        // Readers never wait on the writer
+        let reader = Heap::open_read_only(&path)?;
+        assert_eq!(reader.find("apples")?.len(), 1);
+
+        // A second writer times out with a busy error
+        let busy = HeapLock::acquire(path.join(NB_SUBDIR), std::time::Duration::from_millis(100));
+        assert!(busy.unwrap_err().downcast_ref::<crate::lock::LockError>().is_some());
+
+        drop(writer);
+        Heap::open(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_without_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+
+        let mut heap = Heap::init(&path)?;
+        write_card(&mut heap, "a.md", "apples")?;
+        std::fs::write(path.join("b.md"), "bananas")?;
+        heap.repo.commit_paths(&["b.md"])?;
+        drop(heap);
+
+        let heap = Heap::open_for_search(&path, false)?;
+        assert!(!heap.is_current()?);
+        assert_eq!(heap.find("bananas")?.len(), 0);
+        drop(heap);
+
+        let heap = Heap::open_for_search(&path, true)?;
+        assert!(heap.is_current()?);
+        assert_eq!(heap.find("bananas")?.len(), 1);
+        drop(heap);
+
+        // Nothing changed, so no writer is needed
+        let heap = Heap::open_for_search(&path, true)?;
+        assert!(heap.lock.is_none());
+        drop(heap);
+
+        // Edits in the worktree are only searched for if asked for
+        std::fs::write(path.join("c.md"), "cherries")?;
+        assert!(Heap::open_for_search(&path, true)?.find("cherries")?.is_empty());
+        std::fs::write(path.join(NB_SUBDIR).join(config::CONFIG_FILE), "[search]\nworktree = true\n")?;
+        let head = git2::Repository::open(&path)?.head()?.target();
+        std::fs::write(path.join("b.md"), "blueberries")?;
+        let heap = Heap::open_for_search(&path, true)?;
+        assert_eq!(heap.find("cherries")?.len(), 1);
+        assert_eq!(heap.find("blueberries")?.len(), 1);
+        assert!(heap.find("bananas")?.is_empty());
+        assert!(heap.lock.is_none());
+        drop(heap);
+        // ...without committing them
+        assert_eq!(git2::Repository::open(&path)?.head()?.target(), head);
+        assert!(Heap::open_for_search(&path, false)?.find("cherries")?.is_empty());
+
+        // A busy writer doesn't hold searches up
+        std::fs::write(path.join("d.md"), "dates")?;
+        let mut writer = Heap::open(&path)?;
+        writer.repo.commit_paths(&["d.md"])?;
+        let started = std::time::Instant::now();
+        let heap = Heap::open_for_search(&path, true)?;
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert!(heap.find("dates")?.is_empty());
+        drop(writer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_rename() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        write_card(&mut heap, "a.md", "apples and more apples, enough to be found again")?;
+
+        std::fs::rename(heap.path.join("a.md"), heap.path.join("b.md"))?;
+        heap.repo.commit_paths(&["a.md", "b.md"])?;
+        assert!(heap.repo.diff(Some(&heap.index.payload()?.unwrap()), None)?
+            .contains(&(git2::Delta::Deleted, PathBuf::from("a.md"))));
+        heap.sync()?;
+
+        let found = heap.find("apples")?;
+        assert_eq!(found.iter().map(|r| r.card.path.as_str()).collect::<Vec<_>>(), vec!["b.md"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pull_push() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let remote = dir.path().join("remote.git");
+        git2::Repository::init_bare(&remote)?;
+        let url = remote.to_str().unwrap();
+
+        let mut a = Heap::init(dir.path().join("a"))?;
+        a.add_remote("origin", url)?;
+        write_card(&mut a, "first.md", "written on a")?;
+        a.sync_remote("origin")?;
+
+        let mut b = Heap::init(dir.path().join("b"))?;
+        b.add_remote("origin", url)?;
+        b.pull("origin")?;
+        assert_eq!(std::fs::read_to_string(b.path.join("first.md"))?, "written on a");
+        assert_eq!(b.find("written")?.len(), 1);
+
+        // Diverge on both sides so the next pull needs a three-way merge
+        write_card(&mut b, "second.md", "written on b")?;
+        b.sync_remote("origin")?;
+        write_card(&mut a, "third.md", "also written on a")?;
+        a.sync_remote("origin")?;
+
+        assert!(a.path.join("second.md").exists());
+        assert_eq!(a.find("written")?.len(), 3);
+        assert_eq!(a.repo.head()?.parent_count(), 2);
+
+        b.pull("origin")?;
+        assert!(b.path.join("third.md").exists());
+        assert_eq!(b.find("written")?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pull_conflict() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let remote = dir.path().join("remote.git");
+        git2::Repository::init_bare(&remote)?;
+        let url = remote.to_str().unwrap();
+
+        let mut a = Heap::init(dir.path().join("a"))?;
+        a.add_remote("origin", url)?;
+        write_card(&mut a, "shared.md", "line one\nline two\n")?;
+        a.sync_remote("origin")?;
+
+        let mut b = Heap::init(dir.path().join("b"))?;
+        b.add_remote("origin", url)?;
+        b.pull("origin")?;
+
+        write_card(&mut b, "shared.md", "line one\nline two from b\n")?;
+        b.sync_remote("origin")?;
+        write_card(&mut a, "shared.md", "line one\nline two from a\n")?;
+        assert_eq!(a.sync_remote("origin")?, vec![PathBuf::from("shared.md")]);
+
+        let merged = std::fs::read_to_string(a.path.join("shared.md"))?;
+        assert!(merged.contains("line two from a"));
+        assert!(merged.contains("line two from b"));
+        assert_eq!(a.conflicts()?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_attach() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("projects/plan.md"), "The plan")?;
+
+        let file = dir.path().join("Quarterly report.pdf");
+        std::fs::write(&file, b"%PDF-1.4\n%binary")?;
+        let attachment = heap.attach("projects/plan.md", &file)?;
+        assert!(attachment.starts_with(ATTACHMENTS_DIR));
+        assert!(heap.files()?.contains(&attachment));
+
+        let content = heap.read_card("projects/plan.md")?;
+        assert!(content.ends_with(&format!("\n[Quarterly report.pdf](<../{}>)\n", attachment.display())), "{}", content);
+
+        // The card matches through its link's label and through the attachment
+        assert_eq!(heap.find("quarterly")?.len(), 1);
+        let found = heap.find("filename:quarterly")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].card.path, "projects/plan.md");
+        assert_eq!(found[0].attachment.as_deref(), Some(attachment.to_str().unwrap()));
+        assert_eq!(heap.find("mime:application/pdf")?.len(), 1);
+        assert!(heap.find("binary")?.is_empty());
+
+        let backlinks = heap.backlinks(&attachment)?;
+        assert_eq!(backlinks[0].card.path, "projects/plan.md");
+        assert_eq!(heap.cards()?, vec![PathBuf::from("projects/plan.md")]);
+        assert!(heap.fsck(false)?.is_clean());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_skips_binary() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+
+        std::fs::write(heap.path.join("photo.jpg"), b"\xff\xd8\xff\xe0\0\x10JFIF\0")?;
+        std::fs::write(heap.path.join("old.txt"), b"Notes from the caf\xe9 \x96 cr\xe8me br\xfbl\xe9e")?;
+        std::fs::write(heap.path.join("new.md"), "A new note")?;
+        heap.commit_paths(&["photo.jpg", "old.txt", "new.md"])?;
+
+        assert_eq!(heap.find("br\u{fb}l\u{e9}e")?[0].card.path, "old.txt");
+        assert_eq!(heap.read_card("old.txt")?, "Notes from the caf\u{e9} \u{2013} cr\u{e8}me br\u{fb}l\u{e9}e");
+        assert!(heap.read_card("photo.jpg").is_err());
+        let cards = heap.cards()?;
+        assert!(cards.contains(&PathBuf::from("new.md")));
+        assert!(!cards.contains(&PathBuf::from("photo.jpg")));
+        assert!(heap.fsck(false)?.is_clean());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_card_patterns() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+
+        std::fs::create_dir_all(heap.path.join("build"))?;
+        std::fs::write(heap.path.join(".nbignore"), "build/\n")?;
+        std::fs::write(heap.path.join("build/out.md"), "generated")?;
+        std::fs::write(heap.path.join("data.json"), "{\"generated\": true}")?;
+        std::fs::write(heap.path.join("draft.md"), "generated draft")?;
+        std::fs::write(heap.path.join("notes.txt"), "written by hand")?;
+        heap.commit_paths(&[".nbignore", "build/out.md", "data.json", "draft.md", "notes.txt"])?;
+
+        assert_eq!(heap.cards()?, vec![PathBuf::from("draft.md"), PathBuf::from("notes.txt")]);
+        assert_eq!(heap.find("generated")?.len(), 1);
+
+        // Changing which files are cards reindexes them, whether or not
+        // they changed
+        std::fs::write(heap.path.join(NB_SUBDIR).join(config::CONFIG_FILE), "[cards]\nexclude = [\"draft*\"]\n")?;
+        assert!(!heap.is_current()?);
+        heap.sync()?;
+        assert_eq!(heap.cards()?, vec![PathBuf::from("notes.txt")]);
+        assert!(heap.find("generated")?.is_empty());
+        assert!(heap.fsck(false)?.is_clean());
+
+        std::fs::write(heap.path.join(".nbignore"), "")?;
+        heap.commit_paths(&[".nbignore"])?;
+        assert_eq!(heap.cards()?, vec![PathBuf::from("build/out.md"), PathBuf::from("notes.txt")]);
+        assert_eq!(heap.find("generated")?.len(), 1);
+        assert!(heap.is_current()?);
+
+        Ok(())
+    }
+
+    /// A one page PDF showing `text` in `font`
+    fn pdf(text: &str, font: &str) -> Vec<u8> {
+        let stream = format!("BT /F1 12 Tf 72 712 Td ({}) Tj ET", text);
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_owned(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_owned(),
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents 4 0 R /Resources << /Font << /F1 5 0 R >> >> >>".to_owned(),
+            format!("<< /Length {} >>\nstream\n{}\nendstream", stream.len(), stream),
+            format!("<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>", font),
+        ];
+
+        let mut out = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec!();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).bytes());
+        }
+        let xref = out.len();
+        out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).bytes());
+        for offset in offsets {
+            out.extend(format!("{:010} 00000 n \n", offset).bytes());
+        }
+        out.extend(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).bytes());
+        out
+    }
+
+    #[test]
+    fn test_pdf_text() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("trip.md"), "Trip to Lisbon")?;
+        heap.create_card(Some("other.md"), "Nothing attached")?;
+
+        let file = dir.path().join("itinerary.pdf");
+        std::fs::write(&file, pdf("Departure from gate fourteen", "Helvetica"))?;
+        let attachment = heap.attach("trip.md", &file)?;
+
+        let found = heap.find("fourteen")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].card.path, "trip.md");
+        assert_eq!(found[0].attachment.as_deref(), Some(attachment.to_str().unwrap()));
+        assert!(found[0].snippet.contains("fourteen"), "{}", found[0].snippet);
+
+        // Matches in the card itself name no attachment
+        let found = heap.find("lisbon")?;
+        assert_eq!(found[0].attachment, None);
+
+        // pdf-extract warns about fonts without widths, which mustn't reach stdout
+        let file = dir.path().join("menu.pdf");
+        std::fs::write(&file, pdf("Grilled sardines", "Lisboa-Handwritten"))?;
+        heap.attach("other.md", &file)?;
+        assert_eq!(heap.find("sardines")?[0].card.path, "other.md");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_prepend() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("log.md"), "---\ntitle:   Build log\n---\nstarted")?;
+
+        heap.append_card("log.md", "step one passed")?;
+        heap.append_card("log.md", "step two passed\n")?;
+        heap.prepend_card("log.md", "latest: green")?;
+        assert_eq!(heap.read_card("log.md")?,
+            "---\ntitle:   Build log\n---\nlatest: green\nstarted\nstep one passed\nstep two passed\n");
+        assert_eq!(heap.find("green")?[0].card.path, "log.md");
+        assert!(heap.repo.list_files()?.contains(&PathBuf::from("log.md")));
+
+        heap.create_card(Some("obsidian.md"), "---\ntags:\n  - work\n  - log\n---\nbody\n")?;
+        heap.prepend_card("obsidian.md", "top")?;
+        assert_eq!(heap.read_card("obsidian.md")?, "---\ntags:\n  - work\n  - log\n---\ntop\nbody\n");
+
+        heap.create_card(Some("plain.md"), "")?;
+        heap.prepend_card("plain.md", "first")?;
+        assert_eq!(heap.read_card("plain.md")?, "first\n");
+        assert!(heap.append_card("missing.md", "text").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_saved_queries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("a.md"), "---\ntags: work\n---\nship the release")?;
+        heap.create_card(Some("b.md"), "---\ntags: work, done\n---\nplan the release")?;
+        heap.create_card(Some("c.md"), "---\ntags: home\n---\nfix the sink")?;
+
+        heap.save_query("work", "tags:work")?;
+        heap.save_query("open", "saved:work -tags:done")?;
+        assert!(heap.save_query("broken", "saved:nothing").is_err());
+        assert!(heap.save_query("bad name", "sink").is_err());
+
+        let paths = |results: Vec<index::QueryResult>| results.into_iter().map(|r| r.card.path).collect::<Vec<_>>();
+        assert_eq!(paths(heap.find("saved:open")?), vec!["a.md"]);
+        assert_eq!(heap.find("saved:work")?.len(), 2);
+        assert_eq!(paths(heap.find("saved:work AND plan")?), vec!["b.md"]);
+
+        // Committed, so they travel with the heap, but never cards
+        assert_eq!(heap.queries()?.keys().collect::<Vec<_>>(), vec!["open", "work"]);
+        assert!(heap.repo.list_files()?.contains(&PathBuf::from(saved::QUERIES_FILE)));
+        assert_eq!(heap.cards()?.len(), 3);
+
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,398 @@
+//! `nb http`: a REST API and a small web UI for reading and editing cards
+//! from a browser on the same machine. Everything the UI needs is served
+//! from here, so it works offline.
+//!
+//! Routes, with card paths relative to the heap:
+//!
+//! | route                       | body                    | result                  |
+//! |-----------------------------|-------------------------|-------------------------|
+//! | `GET /`                     |                         | the web UI              |
+//! | `GET /api/search?q=QUERY`   |                         | matching cards          |
+//! | `GET /api/cards`            |                         | every card path         |
+//! | `POST /api/cards`           | `{content, path?}`      | `{path}` of the new card|
+//! | `GET /api/cards/PATH`       |                         | `{path, content}`       |
+//! | `PUT /api/cards/PATH`       | `{content}`             | `{path}`                |
+//! | `DELETE /api/cards/PATH`    |                         | no content              |
+//!
+//! Errors are returned as `{"error": message}`.
+//!
+//! There is no login, so the server only answers requests made to the
+//! address it's bound to, from its own pages or from clients that aren't
+//! browsers: other web pages can't reach it by cross-site requests or by
+//! rebinding a domain name to it. Requests that change cards must be sent
+//! as `application/json`. It binds to loopback addresses only, unless told
+//! otherwise.
+//!
+//! Other nb commands are served over the heap's socket while it runs, as
+//! `nb watch` does.
+
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::heap::{self, Heap};
+use crate::rpc;
+
+pub const DEFAULT_BIND: &str = "127.0.0.1:7878";
+
+const UI: &str = include_str!("http/index.html");
+
+const CARDS: &str = "/api/cards";
+
+#[derive(thiserror::Error, Debug)]
+enum HttpError {
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("card already exists: {0}")]
+    Conflict(String),
+    #[error("method not allowed")]
+    MethodNotAllowed,
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("unsupported content type, expected application/json")]
+    UnsupportedMediaType,
+}
+
+impl HttpError {
+    fn status(&self) -> u16 {
+        match self {
+            HttpError::NotFound(_) => 404,
+            HttpError::BadRequest(_) => 400,
+            HttpError::Conflict(_) => 409,
+            HttpError::MethodNotAllowed => 405,
+            HttpError::Forbidden(_) => 403,
+            HttpError::UnsupportedMediaType => 415,
+        }
+    }
+}
+
+/// A response, before it is written to the client
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn json(status: u16, value: Value) -> Reply {
+        Reply { status, content_type: "application/json", body: value.to_string() }
+    }
+
+    fn html(body: &str) -> Reply {
+        Reply { status: 200, content_type: "text/html; charset=utf-8", body: body.to_owned() }
+    }
+
+    fn empty() -> Reply {
+        Reply { status: 204, content_type: "text/plain", body: String::new() }
+    }
+
+    fn error(e: &anyhow::Error) -> Reply {
+        let status = e.downcast_ref::<HttpError>().map(HttpError::status).unwrap_or(500);
+        Reply::json(status, json!({ "error": format!("{:#}", e) }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewCard {
+    content: String,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CardUpdate {
+    content: String,
+}
+
+/// Headers a request is checked against before it is routed
+#[derive(Debug, Default)]
+struct Headers<'a> {
+    host: Option<&'a str>,
+    origin: Option<&'a str>,
+    content_type: Option<&'a str>,
+}
+
+impl<'a> Headers<'a> {
+    fn of(request: &'a tiny_http::Request) -> Headers<'a> {
+        let header = |name: &'static str| request.headers().iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str());
+        Headers {
+            host: header("Host"),
+            origin: header("Origin"),
+            content_type: header("Content-Type"),
+        }
+    }
+}
+
+/// Serve `heap` on `bind` until interrupted. Addresses other than loopback
+/// ones are refused unless `allow_remote` is set.
+pub fn serve(heap: Heap, bind: &str, allow_remote: bool) -> Result<()> {
+    let remote = bind.to_socket_addrs()
+        .with_context(|| format!("Invalid address {}", bind))?
+        .any(|addr| !addr.ip().is_loopback());
+    if remote && !allow_remote {
+        anyhow::bail!("{} isn't a loopback address and the API has no login, pass --allow-remote to serve it anyway", bind);
+    }
+
+    let server = Server::http(bind)
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .with_context(|| format!("Failed to bind {}", bind))?;
+    let addr = server.server_addr().to_ip().context("Not bound to an IP address")?;
+    if remote {
+        eprintln!("warning: anyone who can reach {} can read and change every card", addr);
+    }
+    println!("serving {} on http://{}", heap.path().display(), addr);
+
+    // Other nb commands go through the rpc server while this holds the heap
+    let root = heap.path().to_owned();
+    let heap = Arc::new(Mutex::new(heap));
+    let _server = rpc::Server::spawn(heap.clone(), rpc::socket_path(&root))?;
+
+    for mut request in server.incoming_requests() {
+        let mut body = String::new();
+        let reply = match check_request(addr, request.method(), &Headers::of(&request)) {
+            Err(e) => Reply::error(&e),
+            Ok(()) => match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => route(&mut heap.lock().expect("heap lock poisoned"), request.method(), request.url(), &body)
+                    .unwrap_or_else(|e| Reply::error(&e)),
+                Err(e) => Reply::error(&HttpError::BadRequest(e.to_string()).into()),
+            },
+        };
+        log::info!("{} {} {}", request.method(), request.url(), reply.status);
+
+        let header = Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes())
+            .expect("content type is a valid header");
+        let response = Response::from_string(reply.body)
+            .with_status_code(reply.status)
+            .with_header(header);
+
+        // The client going away shouldn't stop the server
+        if let Err(e) = request.respond(response) {
+            log::warn!("failed to send response: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuse requests made through another host name, from another site's
+/// pages, or changing cards with a body that isn't declared as JSON
+fn check_request(addr: SocketAddr, method: &Method, headers: &Headers) -> Result<()> {
+    let host = headers.host.unwrap_or_default();
+    if !is_own_host(addr, host) {
+        return Err(HttpError::Forbidden(format!("unknown host {:?}", host)).into());
+    }
+
+    if let Some(origin) = headers.origin {
+        if origin.strip_prefix("http://") != Some(host) {
+            return Err(HttpError::Forbidden(format!("cross-origin request from {}", origin)).into());
+        }
+    }
+
+    if matches!(method, Method::Post | Method::Put | Method::Delete) {
+        let json = headers.content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+        if !json {
+            return Err(HttpError::UnsupportedMediaType.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `host`, from a Host header, names the address the server is
+/// bound to. Names other than `localhost` could be rebound to it by anyone,
+/// so only addresses are accepted, and any address when bound to all of
+/// them.
+fn is_own_host(addr: SocketAddr, host: &str) -> bool {
+    match host.parse::<SocketAddr>() {
+        Ok(host) => host == addr || (addr.ip().is_unspecified() && host.port() == addr.port()),
+        Err(_) => addr.ip().is_loopback() && host == format!("localhost:{}", addr.port()),
+    }
+}
+
+fn route(heap: &mut Heap, method: &Method, url: &str, body: &str) -> Result<Reply> {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+
+    match (method, path) {
+        (Method::Get, "/") | (Method::Get, "/index.html") => Ok(Reply::html(UI)),
+        (Method::Get, "/api/search") => {
+            let q = query.and_then(|query| query_param(query, "q"))
+                .ok_or_else(|| HttpError::BadRequest("missing parameter: q".to_owned()))?;
+            Ok(Reply::json(200, serde_json::to_value(heap.find(&q)?)?))
+        }
+        (Method::Get, CARDS) => Ok(Reply::json(200, serde_json::to_value(heap.cards()?)?)),
+        (Method::Post, CARDS) => {
+            let card: NewCard = parse_body(body)?;
+            if let Some(path) = &card.path {
+                check_path(path)?;
+                if heap.path().join(path).exists() {
+                    return Err(HttpError::Conflict(path.display().to_string()).into());
+                }
+            }
+            let path = heap.create_card(card.path, &card.content)?;
+            Ok(Reply::json(201, json!({ "path": path })))
+        }
+        (_, path) if path.starts_with(CARDS) && path[CARDS.len()..].starts_with('/') => {
+            let card = card_path(&path[CARDS.len() + 1..])?;
+            if !heap.path().join(&card).is_file() {
+                return Err(HttpError::NotFound(card.display().to_string()).into());
+            }
+
+            match method {
+                Method::Get => {
+                    let content = heap.read_card(&card)?;
+                    Ok(Reply::json(200, json!({ "path": card, "content": content })))
+                }
+                Method::Put => {
+                    let update: CardUpdate = parse_body(body)?;
+                    heap.update_card(&card, &update.content)?;
+                    Ok(Reply::json(200, json!({ "path": card })))
+                }
+                Method::Delete => {
+                    heap.delete_card(&card)?;
+                    Ok(Reply::empty())
+                }
+                _ => Err(HttpError::MethodNotAllowed.into()),
+            }
+        }
+        (_, "/") | (_, "/api/search") | (_, CARDS) => Err(HttpError::MethodNotAllowed.into()),
+        (_, path) => Err(HttpError::NotFound(path.to_owned()).into()),
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    serde_json::from_str(body)
+        .map_err(|e| HttpError::BadRequest(e.to_string()).into())
+}
+
+/// Card path from the encoded remainder of a `/api/cards/` url
+fn card_path(encoded: &str) -> Result<PathBuf> {
+    let decoded = percent_decode_str(encoded).decode_utf8()
+        .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+    let path = PathBuf::from(decoded.as_ref());
+    check_path(&path)?;
+    Ok(path)
+}
+
+/// Reject paths that would reach outside the heap, or into its git and
+/// index internals, before touching the disk
+fn check_path(path: &Path) -> Result<()> {
+    heap::check_card_path(path)
+        .map_err(|e| HttpError::BadRequest(format!("invalid card path: {}", e)).into())
+}
+
+/// Decoded value of `name` in a `application/x-www-form-urlencoded` query
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| {
+            let value = value.replace('+', " ");
+            percent_decode_str(&value).decode_utf8_lossy().into_owned()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(heap: &mut Heap, method: Method, url: &str, body: &str) -> Reply {
+        route(heap, &method, url, body).unwrap_or_else(|e| Reply::error(&e))
+    }
+
+    fn json_body(reply: &Reply) -> Value {
+        serde_json::from_str(&reply.body).unwrap()
+    }
+
+    #[test]
+    fn rest_api() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+
+        let reply = call(&mut heap, Method::Post, CARDS, r#"{"path": "dir/a b.md", "content": "apples"}"#);
+        assert_eq!(reply.status, 201);
+        assert_eq!(json_body(&reply)["path"], "dir/a b.md");
+        assert_eq!(call(&mut heap, Method::Post, CARDS, r#"{"path": "dir/a b.md", "content": "x"}"#).status, 409);
+
+        let reply = call(&mut heap, Method::Get, "/api/cards/dir/a%20b.md", "");
+        assert_eq!(json_body(&reply)["content"], "apples");
+
+        let reply = call(&mut heap, Method::Get, "/api/search?q=apples", "");
+        assert_eq!(json_body(&reply)[0]["card"]["path"], "dir/a b.md");
+
+        let reply = call(&mut heap, Method::Put, "/api/cards/dir/a%20b.md", r#"{"content": "pears"}"#);
+        assert_eq!(reply.status, 200);
+        assert_eq!(json_body(&call(&mut heap, Method::Get, "/api/search?q=pears", "")).as_array().unwrap().len(), 1);
+        assert_eq!(json_body(&call(&mut heap, Method::Get, "/api/search?q=apples", "")), json!([]));
+
+        let listed = |heap: &mut Heap| json_body(&call(heap, Method::Get, CARDS, "")).as_array().unwrap().contains(&json!("dir/a b.md"));
+        assert!(listed(&mut heap));
+        assert_eq!(call(&mut heap, Method::Delete, "/api/cards/dir/a%20b.md", "").status, 204);
+        assert!(!listed(&mut heap));
+        assert_eq!(call(&mut heap, Method::Get, "/api/cards/dir/a%20b.md", "").status, 404);
+
+        assert_eq!(call(&mut heap, Method::Put, CARDS, "").status, 405);
+        assert_eq!(call(&mut heap, Method::Post, CARDS, "not json").status, 400);
+        assert_eq!(call(&mut heap, Method::Post, CARDS, r#"{"path": "/etc/passwd", "content": ""}"#).status, 400);
+        assert_eq!(call(&mut heap, Method::Get, "/api/cards/../secret", "").status, 400);
+
+        // Repository and index internals aren't cards
+        let git_config = std::fs::read(heap.path().join(".git/config"))?;
+        assert_eq!(call(&mut heap, Method::Put, "/api/cards/.git/config", r#"{"content": ""}"#).status, 400);
+        assert_eq!(call(&mut heap, Method::Delete, "/api/cards/.git/HEAD", "").status, 400);
+        assert_eq!(call(&mut heap, Method::Get, "/api/cards/.nb/schema_version", "").status, 400);
+        assert_eq!(call(&mut heap, Method::Get, "/api/cards/dir/%2Egit/config", "").status, 400);
+        assert_eq!(call(&mut heap, Method::Post, CARDS, r#"{"path": ".nb/x.md", "content": ""}"#).status, 400);
+        assert_eq!(std::fs::read(heap.path().join(".git/config"))?, git_config);
+        assert!(heap.path().join(".git/HEAD").is_file());
+        assert_eq!(call(&mut heap, Method::Get, "/", "").content_type, "text/html; charset=utf-8");
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_foreign_requests() {
+        let addr: SocketAddr = "127.0.0.1:7878".parse().unwrap();
+        let check = |method, host, origin, content_type| {
+            let headers = Headers { host, origin, content_type };
+            check_request(addr, &method, &headers).map_err(|e| e.downcast::<HttpError>().unwrap().status())
+        };
+        let json = Some("application/json; charset=utf-8");
+
+        assert!(check(Method::Get, Some("127.0.0.1:7878"), None, None).is_ok());
+        assert!(check(Method::Get, Some("localhost:7878"), None, None).is_ok());
+        assert!(check(Method::Put, Some("127.0.0.1:7878"), Some("http://127.0.0.1:7878"), json).is_ok());
+
+        // DNS rebinding and cross-site requests
+        assert_eq!(check(Method::Get, Some("evil.example:7878"), None, None), Err(403));
+        assert_eq!(check(Method::Get, None, None, None), Err(403));
+        assert_eq!(check(Method::Post, Some("127.0.0.1:7878"), Some("http://evil.example"), json), Err(403));
+        assert_eq!(check(Method::Post, Some("127.0.0.1:7878"), Some("null"), json), Err(403));
+        assert_eq!(check(Method::Post, Some("127.0.0.1:7878"), None, Some("text/plain")), Err(415));
+        assert_eq!(check(Method::Delete, Some("127.0.0.1:7878"), None, None), Err(415));
+
+        let all: SocketAddr = "0.0.0.0:7878".parse().unwrap();
+        assert!(is_own_host(all, "192.168.1.20:7878"));
+        assert!(!is_own_host(all, "evil.example:7878"));
+        assert!(!is_own_host(all, "localhost:7878"));
+    }
+
+    #[test]
+    fn decode_queries() {
+        assert_eq!(query_param("x=1&q=tags%3Afruit+apples", "q").as_deref(), Some("tags:fruit apples"));
+        assert_eq!(query_param("x=1", "q"), None);
+    }
+}
@@ -0,0 +1,573 @@
+//! `nb import`: bring notes kept elsewhere into the heap. Imported cards get
+//! new ULID paths and are committed together in a single commit, followed
+//! by a single sync.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::frontmatter::FrontMatter;
+use crate::heap::{self, Heap};
+use crate::links;
+use crate::text;
+
+mod rtf;
+pub mod nvalt;
+pub mod simplenote;
+pub mod enex;
+pub mod jex;
+pub mod jsonl;
+
+/// Extensions imported from note folders
+const NOTE_EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// What an import did
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Summary {
+    pub imported: usize,
+    pub skipped: Vec<PathBuf>, // Source files that weren't notes
+}
+
+/// Cards written by an import, committed together once it's done. If the
+/// import fails before then, the files it wrote are removed again.
+struct Batch<'a> {
+    heap: &'a mut Heap,
+    paths: Vec<PathBuf>,   // Cards and attachments to commit
+    written: Vec<PathBuf>, // Files this batch created
+    cards: usize,
+}
+
+impl<'a> Batch<'a> {
+    fn new(heap: &'a mut Heap) -> Batch<'a> {
+        Batch { heap, paths: vec!(), written: vec!(), cards: 0 }
+    }
+
+    fn add<C: AsRef<[u8]>>(&mut self, path: &Path, content: C) -> Result<()> {
+        let path = self.heap.write_card(Some(path), content)?;
+        self.written.push(path.clone());
+        self.paths.push(path);
+        self.cards += 1;
+        Ok(())
+    }
+
+    /// Store an attachment, returning its path relative to the heap
+    fn attach(&mut self, name: &str, data: &[u8]) -> Result<PathBuf> {
+        let path = heap::attachment_path(name, data)?;
+        if !self.heap.path().join(&path).exists() {
+            self.heap.write_attachment(name, data)?;
+            self.written.push(path.clone());
+        }
+        if !self.paths.contains(&path) {
+            self.paths.push(path.clone());
+        }
+        Ok(path)
+    }
+
+    /// Write a file at exactly `path`, such as a restored attachment
+    fn restore(&mut self, path: &Path, data: &[u8]) -> Result<()> {
+        let path = self.heap.write_card(Some(path), data)?;
+        self.written.push(path.clone());
+        self.paths.push(path);
+        Ok(())
+    }
+
+    /// Commit and index everything written so far, returning the number of cards
+    fn commit(mut self) -> Result<usize> {
+        self.heap.commit_paths(&self.paths)?;
+        self.written.clear();
+        Ok(self.cards)
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        for path in &self.written {
+            // Unless committing got as far as the commit, and only the sync failed
+            if self.heap.read_committed(path).is_err() {
+                let _ = std::fs::remove_file(self.heap.path().join(path));
+            }
+        }
+    }
+}
+
+/// A note found in a source folder
+#[derive(Debug)]
+struct Source {
+    path: PathBuf, // Relative to the source folder
+    card: PathBuf, // New path in the heap
+    created: SystemTime,
+    modified: SystemTime,
+}
+
+/// Import `source` with the importer for `format`, as `nb import` names them
+pub fn import(heap: &mut Heap, format: &str, source: &Path) -> Result<Summary> {
+    let importer = match format {
+        "dir" => dir,
+        "nvalt" => nvalt::import,
+        "simplenote" => simplenote::import,
+        "enex" => enex::import,
+        "jex" => jex::import,
+        "jsonl" => jsonl::import,
+        _ => bail!("Unknown import format: {}", format),
+    };
+    importer(heap, source)
+}
+
+/// Import every note under `source`, a plain Markdown folder or an Obsidian
+/// vault. Links between the notes are rewritten to their new paths, YAML
+/// front matter is flattened and inline `#tags` are added to `tags`. Other
+/// files, such as images, become attachments, and `[[links]]` to them
+/// Markdown links.
+pub fn dir(heap: &mut Heap, source: &Path) -> Result<Summary> {
+    folder(heap, source, NOTE_EXTENSIONS, read_text, true)
+}
+
+/// Reads a note found in a folder, returning its text and any tags stored
+/// outside of it
+type Reader = fn(&Path) -> Result<(String, Vec<String>)>;
+
+fn read_text(path: &Path) -> Result<(String, Vec<String>)> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    match text::decode(&data).into_string() {
+        Some(text) => Ok((text, vec!())),
+        None => bail!("{} looks binary, not a note", path.display()),
+    }
+}
+
+/// Import the files under `source` with one of `extensions`, each read by
+/// `read`. With `attach`, other files are attached, otherwise they're
+/// skipped. Files the heap's exclude globs match, or ignored by the ignore
+/// files in `source`, are always skipped.
+fn folder(heap: &mut Heap, source: &Path, extensions: &[&str], read: Reader, attach: bool) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let mut notes = vec!();
+    let mut others = vec!();
+    let filter = heap.card_filter(source)?;
+
+    for path in walk(source)? {
+        let relative = path.strip_prefix(source)?.to_owned();
+        let is_note = path.extension()
+            .is_some_and(|ext| extensions.contains(&ext.to_string_lossy().to_lowercase().as_str()));
+        if filter.is_excluded(&relative) || (!is_note && !attach) {
+            summary.skipped.push(relative);
+            continue;
+        }
+        if !is_note {
+            others.push(relative);
+            continue;
+        }
+
+        let metadata = std::fs::metadata(&path)?;
+        let modified = metadata.modified()?;
+        notes.push(Source {
+            path: relative,
+            card: new_card_path(),
+            created: metadata.created().unwrap_or(modified),
+            modified,
+        });
+    }
+
+    let mut names = Names::new(&notes);
+    let mut batch = Batch::new(heap);
+
+    for path in others {
+        let data = std::fs::read(source.join(&path))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let attachment = batch.attach(&name, &data)?;
+        names.attachment(&path, attachment, infer::is_image(&data));
+    }
+
+    for note in &notes {
+        let (content, tags) = read(&source.join(&note.path))?;
+        batch.add(&note.card, convert(note, &content, &tags, &names))?;
+    }
+
+    summary.imported = batch.commit()?;
+    Ok(summary)
+}
+
+fn new_card_path() -> PathBuf {
+    PathBuf::from(format!("{}.md", rusty_ulid::generate_ulid_string()))
+}
+
+/// Every file under `dir`, in a stable order, skipping hidden files and
+/// directories such as `.obsidian` and `.git`
+fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut files = vec!();
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            files.extend(walk(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Resolves link targets the way Obsidian does: by path within the vault,
+/// or failing that by file name alone. Both ignore case, and for notes the
+/// extension.
+struct Names {
+    paths: BTreeMap<String, String>,
+    stems: BTreeMap<String, String>,
+    attachments: BTreeMap<String, (PathBuf, bool)>, // Path in the heap and whether it's an image
+}
+
+impl Names {
+    fn new(notes: &[Source]) -> Names {
+        let mut paths = BTreeMap::new();
+        let mut stems = BTreeMap::new();
+
+        for note in notes {
+            let key = links::key(&note.card);
+            let name = note.path.with_extension("").to_string_lossy().to_lowercase();
+            let stem = note.path.file_stem().unwrap_or_default().to_string_lossy().to_lowercase();
+            paths.insert(name, key.clone());
+            // Notes come sorted, so the shallowest of same-named notes wins
+            stems.entry(stem).or_insert(key);
+        }
+
+        Names { paths, stems, attachments: BTreeMap::new() }
+    }
+
+    /// Add the file at `path` in the source folder, attached as `attachment`
+    fn attachment(&mut self, path: &Path, attachment: PathBuf, image: bool) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+        self.attachments.insert(path.to_string_lossy().to_lowercase(), (attachment.clone(), image));
+        self.attachments.entry(name).or_insert((attachment, image));
+    }
+
+    /// Key of the card `target` refers to, if it was imported
+    fn resolve(&self, target: &str) -> Option<&str> {
+        let target = target.trim().to_lowercase();
+        let target = target.strip_suffix(".md").unwrap_or(&target);
+        self.paths.get(target)
+            .or_else(|| self.stems.get(target))
+            .map(String::as_str)
+    }
+
+    /// Attachment `target` refers to, and whether it's an image
+    fn resolve_attachment(&self, target: &str) -> Option<&(PathBuf, bool)> {
+        self.attachments.get(&target.trim().to_lowercase())
+    }
+}
+
+fn convert(note: &Source, content: &str, tags: &[String], names: &Names) -> String {
+    let (mut frontmatter, body) = yaml_front_matter(content);
+
+    for tag in tags.iter().cloned().chain(inline_tags(body)) {
+        frontmatter.add_tag(&tag);
+    }
+
+    if frontmatter.get("title").is_none() {
+        let title = note.path.file_stem().unwrap_or_default().to_string_lossy();
+        frontmatter.set("title", &title);
+    }
+    if frontmatter.get("created").is_none() {
+        frontmatter.set("created", &timestamp(note.created));
+    }
+    if frontmatter.get("modified").is_none() {
+        frontmatter.set("modified", &timestamp(note.modified));
+    }
+
+    frontmatter.render(&convert_links(body, names))
+}
+
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `date` in the same form as `timestamp`, or as it is if it isn't RFC 3339
+fn rfc3339(date: &str) -> String {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|_| date.to_owned())
+}
+
+/// Rewrite `[[Note Name#Heading|alias]]` links to the imported cards'
+/// keys, keeping what the reader saw as the label. Links to notes that
+/// weren't imported are left alone.
+fn convert_links(body: &str, names: &Names) -> String {
+    let mut converted = String::with_capacity(body.len());
+    let mut offset = 0;
+
+    for link in links::extract(body) {
+        // Headings and block references have no equivalent
+        let name = link.target.split(['#', '^']).next().unwrap_or("");
+        let embed = body[..link.start].ends_with('!');
+        let label = link.label.as_deref().unwrap_or(&link.target);
+
+        let replacement = match (names.resolve(name), names.resolve_attachment(name)) {
+            // Embedding a note becomes a plain link to it
+            (Some(key), _) => links::render(key, Some(label)),
+            (None, Some((path, image))) => format!("{}[{}]({})",
+                if embed && *image { "!" } else { "" },
+                label.replace('[', "\\[").replace(']', "\\]"),
+                attachment_url(path)),
+            (None, None) => continue,
+        };
+
+        let start = if embed { link.start - 1 } else { link.start };
+        converted.push_str(&body[offset..start]);
+        converted.push_str(&replacement);
+        offset = link.end;
+    }
+
+    converted.push_str(&body[offset..]);
+    converted
+}
+
+/// Rewrite Markdown links `[label](PREFIXid)` whose target starts with
+/// `prefix`, replacing each with what `replace` returns for its id and
+/// label. Links it returns None for are kept as they are.
+fn replace_markdown_links<F>(content: &str, prefix: &str, replace: F) -> String
+    where F: Fn(&str, &str) -> Option<String>
+{
+    let prefix = format!("]({}", prefix);
+    let mut converted = String::with_capacity(content.len());
+    let mut offset = 0;
+
+    while let Some(found) = content[offset..].find(&prefix) {
+        let label_end = offset + found;
+        let id_start = label_end + prefix.len();
+        let link = content[id_start..].find(')').and_then(|end| {
+            let id = &content[id_start..id_start + end];
+            let label_start = content[offset..label_end].rfind('[')? + offset;
+            let replacement = replace(id, &content[label_start + 1..label_end])?;
+            Some((label_start, replacement, id_start + end + 1))
+        });
+
+        match link {
+            Some((start, replacement, end)) => {
+                converted.push_str(&content[offset..start]);
+                converted.push_str(&replacement);
+                offset = end;
+            }
+            None => {
+                converted.push_str(&content[offset..id_start]);
+                offset = id_start;
+            }
+        }
+    }
+
+    converted.push_str(&content[offset..]);
+    converted
+}
+
+/// Url of an attachment in a Markdown link from a card at the heap root
+fn attachment_url(path: &Path) -> String {
+    format!("<{}>", path.display())
+}
+
+/// Front matter from the YAML block Obsidian and most other tools write.
+/// Lists become comma separated values, quotes are dropped, and anything
+/// nested deeper is kept as flat text.
+fn yaml_front_matter(content: &str) -> (FrontMatter, &str) {
+    let mut frontmatter = FrontMatter::default();
+    let rest = match content.strip_prefix("---\n").or_else(|| content.strip_prefix("---\r\n")) {
+        Some(rest) => rest,
+        None => return (frontmatter, content),
+    };
+
+    let mut attributes: Vec<(String, Vec<String>)> = vec!();
+    let mut offset = content.len() - rest.len();
+
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if line.trim_end() == "---" {
+            for (key, values) in attributes {
+                let key = if key == "tag" { "tags".to_owned() } else { key };
+                if key == "tags" {
+                    let values: Vec<&str> = values.iter()
+                        .flat_map(|v| v.split(','))
+                        .map(|t| t.trim().trim_start_matches('#'))
+                        .filter(|t| !t.is_empty())
+                        .collect();
+                    frontmatter.set_tags(&values);
+                } else if !values.is_empty() {
+                    frontmatter.set(&key, &values.join(", "));
+                }
+            }
+            return (frontmatter, &content[offset..]);
+        }
+
+        let item = trimmed.strip_prefix("- ").or_else(|| if trimmed == "-" { Some("") } else { None });
+        match (item, line.starts_with(char::is_whitespace), trimmed.split_once(':')) {
+            (_, _, _) if trimmed.is_empty() || trimmed.starts_with('#') => {}
+            // An item of the list under the previous key
+            (Some(item), _, _) => {
+                if let Some((_, values)) = attributes.last_mut() {
+                    values.push(unquote(item).to_owned());
+                }
+            }
+            // A nested map or a continued scalar
+            (None, true, _) => {
+                if let Some((_, values)) = attributes.last_mut() {
+                    values.push(trimmed.to_owned());
+                }
+            }
+            (None, false, Some((key, value))) => {
+                let value = unquote(value.trim());
+                let values = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                    Some(list) => list.split(',').map(|v| unquote(v.trim()).to_owned()).filter(|v| !v.is_empty()).collect(),
+                    None if value.is_empty() || value == "|" || value == ">" => vec!(),
+                    None => vec!(value.to_owned()),
+                };
+                attributes.push((key.trim().to_owned(), values));
+            }
+            // Not front matter after all
+            (None, false, None) => return (FrontMatter::default(), content),
+        }
+    }
+
+    (FrontMatter::default(), content)
+}
+
+fn unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(value)
+}
+
+/// Obsidian style `#tags` in the body, outside code. Tags may contain
+/// letters, digits, `_`, `-` and `/`, but can't be all digits.
+fn inline_tags(body: &str) -> Vec<String> {
+    let mut tags: Vec<String> = vec!();
+    let mut fenced = false;
+
+    for line in body.lines() {
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            continue;
+        }
+
+        // Drop inline code spans, the odd pieces between backticks
+        let text: String = line.split('`').step_by(2).collect::<Vec<_>>().join(" ");
+
+        for word in text.split_whitespace() {
+            let tag: String = match word.strip_prefix('#') {
+                Some(rest) => rest.chars()
+                    .take_while(|c| c.is_alphanumeric() || "_-/".contains(*c))
+                    .collect(),
+                None => continue,
+            };
+            let tag = tag.trim_end_matches('/');
+            if !tag.is_empty() && !tag.chars().all(|c| c.is_ascii_digit()) && !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_owned());
+            }
+        }
+    }
+
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatten_yaml() {
+        let (fm, body) = yaml_front_matter("---\naliases:\n  - Foo\n  - \"Bar\"\ntag: [a, '#b']\nempty:\n---\nbody");
+        assert_eq!(fm.get("aliases"), Some("Foo, Bar"));
+        assert_eq!(fm.tags(), vec!["a", "b"]);
+        assert_eq!(fm.get("empty"), None);
+        assert_eq!(body, "body");
+
+        let (fm, body) = yaml_front_matter("---\nnot yaml\n---\n");
+        assert!(fm.is_empty());
+        assert_eq!(body, "---\nnot yaml\n---\n");
+    }
+
+    #[test]
+    fn find_inline_tags() {
+        let body = "# Heading\n#todo and #area/work, not #123 or `#code`\n```\n#fenced\n```\n#todo";
+        assert_eq!(inline_tags(body), vec!["todo", "area/work"]);
+    }
+
+    #[test]
+    fn failed_import_leaves_nothing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("taken.md"), "already here")?;
+
+        let mut batch = Batch::new(&mut heap);
+        batch.add(Path::new("new.md"), "imported")?;
+        let attachment = batch.attach("photo.png", b"\x89PNG")?;
+        assert!(batch.add(Path::new("taken.md"), "imported too").is_err());
+        drop(batch);
+
+        assert!(!heap.path().join("new.md").exists());
+        assert!(!heap.path().join(&attachment).exists());
+        assert_eq!(heap.read_card("taken.md")?, "already here");
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_vault() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let vault = tmp.path().join("vault");
+        std::fs::create_dir_all(vault.join(".obsidian"))?;
+        std::fs::create_dir_all(vault.join("sub"))?;
+        std::fs::write(vault.join(".obsidian/app.json"), "{}")?;
+        std::fs::write(vault.join("Home.md"), "---\ntags:\n  - index\n---\nSee [[Other Note#Part|other]], ![[sub/other note]] and [[Missing]] #home")?;
+        std::fs::write(vault.join("sub/Other Note.md"), "back to [[home]]\n\n![[Photo.png]] from [[sub/plan (v2).pdf|the plan]]")?;
+        std::fs::write(vault.join("sub/photo.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+        std::fs::write(vault.join("sub/plan (v2).pdf"), b"%PDF-1.4\n")?;
+        std::fs::create_dir_all(vault.join("private"))?;
+        std::fs::write(vault.join("private/secret.md"), "hidden")?;
+        std::fs::write(vault.join(".gitignore"), "private/\n")?;
+
+        let mut heap = Heap::init(tmp.path().join("heap"))?;
+        let summary = dir(&mut heap, &vault)?;
+        assert_eq!(summary.imported, 2);
+        assert_eq!(summary.skipped, vec![PathBuf::from("private/secret.md")]);
+
+        let home = &heap.find("tags:index")?[0].card.path;
+        let content = heap.read_card(home)?;
+        let (fm, body) = FrontMatter::parse(&content);
+        let fm = fm.unwrap();
+        assert_eq!(fm.tags(), vec!["index", "home"]);
+        assert!(fm.get("created").is_some() && fm.get("modified").unwrap().ends_with('Z'));
+
+        let links = heap.links(home)?;
+        assert_eq!(links[0].link.label.as_deref(), Some("other"));
+        assert_eq!(links[1].link.label.as_deref(), Some("sub/other note"));
+        assert_eq!(links[0].path, links[1].path);
+        assert!(links[0].path.is_some());
+        assert!(body.contains("], [[") && body.contains("and [[Missing]]"));
+
+        let other = links[0].path.as_ref().unwrap();
+        assert_eq!(heap.backlinks(other)?.len(), 1);
+        assert_eq!(heap.links(other)?[0].path, Some(PathBuf::from(home)));
+
+        // Other files are attached, and found through the cards linking them
+        let content = heap.read_card(other)?;
+        assert!(content.contains("![Photo.png](<attachments/"), "{}", content);
+        assert!(content.contains("/plan (v2).pdf>)"), "{}", content);
+        assert!(!content.contains("[[sub/plan"));
+        assert_eq!(heap.find("filename:photo")?[0].card.path, other.to_string_lossy());
+        assert_eq!(heap.files()?.iter().filter(|path| heap::is_attachment(path)).count(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+//! Evernote's ENEX export: an XML file holding one notebook's notes, each
+//! with an ENML (XHTML) body and its attachments inline as base64.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::{attachment_url, new_card_path, Batch, Summary};
+use crate::frontmatter::FrontMatter;
+use crate::heap::Heap;
+
+/// Timestamps in ENEX files, e.g. 20200131T235959Z
+const DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Debug, Default)]
+struct Note {
+    title: String,
+    content: String, // ENML
+    created: Option<String>,
+    updated: Option<String>,
+    tags: Vec<String>,
+    resources: Vec<Resource>,
+}
+
+#[derive(Debug, Default)]
+struct Resource {
+    data: Vec<u8>,
+    mime: String,
+    file_name: Option<String>,
+}
+
+/// Import every note in an ENEX file. Evernote names the file after the
+/// notebook it exported, so its name is added to each note's tags. Notes
+/// are read and added one at a time, as exports with attachments can be
+/// much larger than memory.
+pub fn import(heap: &mut Heap, file: &Path) -> Result<Summary> {
+    let notebook = file.file_stem().map(|stem| stem.to_string_lossy().into_owned());
+
+    let mut batch = Batch::new(heap);
+    parse(file, |note| add_note(&mut batch, note, notebook.as_deref()))?;
+
+    Ok(Summary { imported: batch.commit()?, ..Summary::default() })
+}
+
+fn add_note(batch: &mut Batch<'_>, note: Note, notebook: Option<&str>) -> Result<()> {
+    // ENML refers to attachments by the MD5 of their data
+    let mut attachments = HashMap::new();
+    for resource in &note.resources {
+        let name = resource.file_name.clone()
+            .unwrap_or_else(|| format!("attachment.{}", extension(&resource.mime)));
+        let path = batch.attach(&name, &resource.data)?;
+        attachments.insert(format!("{:x}", md5::compute(&resource.data)), (path, resource.mime.as_str()));
+    }
+
+    let mut frontmatter = FrontMatter::default();
+    frontmatter.set("title", &note.title);
+    let tags: Vec<&str> = notebook.into_iter().chain(note.tags.iter().map(String::as_str)).collect();
+    frontmatter.set_tags(&tags);
+    for (key, date) in &[("created", &note.created), ("modified", &note.updated)] {
+        if let Some(date) = date {
+            frontmatter.set(key, &timestamp(date));
+        }
+    }
+
+    let body = enml_to_markdown(&note.content, &attachments);
+    batch.add(&new_card_path(), frontmatter.render(&body))
+}
+
+/// Read the notes in `file`, passing each to `each` as soon as it's read
+fn parse<F: FnMut(Note) -> Result<()>>(file: &Path, mut each: F) -> Result<()> {
+    let not_enex = || format!("{} isn't an Evernote export", file.display());
+    let mut reader = Reader::from_file(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    reader.trim_text(true);
+
+    let mut note = Note::default();
+    let mut resource = Resource::default();
+    let mut path: Vec<String> = vec!();
+    let mut text = String::new();
+    let mut buf = vec!();
+
+    loop {
+        match reader.read_event(&mut buf).with_context(not_enex)? {
+            Event::Start(e) => {
+                path.push(reader.decode(e.name()).with_context(not_enex)?.to_owned());
+                text.clear();
+            }
+            Event::Text(e) => text.push_str(&e.unescape_and_decode(&reader).with_context(not_enex)?),
+            // Note content is XHTML wrapped in CDATA, which isn't escaped
+            Event::CData(e) => text.push_str(reader.decode(&e).with_context(not_enex)?),
+            Event::End(_) => {
+                let value = std::mem::take(&mut text);
+                let element: Vec<&str> = path.iter().map(String::as_str).collect();
+                match element.as_slice() {
+                    [.., "note", "title"] => note.title = value,
+                    [.., "note", "content"] => note.content = value,
+                    [.., "note", "created"] => note.created = Some(value),
+                    [.., "note", "updated"] => note.updated = Some(value),
+                    [.., "note", "tag"] => note.tags.push(value),
+                    [.., "resource", "data"] => {
+                        let data: String = value.split_whitespace().collect();
+                        resource.data = base64::decode(&data).context("Invalid attachment data").with_context(not_enex)?;
+                    }
+                    [.., "resource", "mime"] => resource.mime = value,
+                    [.., "resource-attributes", "file-name"] => resource.file_name = Some(value),
+                    [.., "note", "resource"] => note.resources.push(std::mem::take(&mut resource)),
+                    [.., "note"] => each(std::mem::take(&mut note))?,
+                    _ => {}
+                }
+                path.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// `20200131T235959Z` as `2020-01-31T23:59:59Z`, or as it is if it doesn't parse
+fn timestamp(date: &str) -> String {
+    NaiveDateTime::parse_from_str(date, DATE_FORMAT)
+        .map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_else(|_| date.to_owned())
+}
+
+fn extension(mime: &str) -> &str {
+    match mime {
+        "image/jpeg" => "jpg",
+        "application/octet-stream" | "" => "bin",
+        "text/plain" => "txt",
+        mime => mime.rsplit('/').next().unwrap_or("bin"),
+    }
+}
+
+/// Markdown from an ENML body, with `<en-media>` pointing at `attachments`,
+/// keyed by MD5, and `<en-todo>` checkboxes as task list items
+fn enml_to_markdown(enml: &str, attachments: &HashMap<String, (PathBuf, &str)>) -> String {
+    // The XML prolog and doctype come before the root element
+    let html = match enml.find("<en-note") {
+        Some(start) => &enml[start..],
+        None => enml,
+    };
+
+    // html2md would escape the markdown for media and checkboxes, so they're
+    // left as placeholders, filled in after it's done
+    let mut placeholders = Placeholders::new(enml);
+    let html = replace_elements(html, "en-media", |attributes| {
+        let hash = attribute(attributes, "hash").unwrap_or_default();
+        match attachments.get(&hash) {
+            Some((path, mime)) => {
+                let embed = if mime.starts_with("image/") { "!" } else { "" };
+                placeholders.add(format!("{}[{}]({})", embed, file_name(path), attachment_url(path)))
+            }
+            None => String::new(),
+        }
+    });
+    let html = replace_elements(&html, "en-todo", |attributes| {
+        match attribute(attributes, "checked").as_deref() {
+            Some("true") => placeholders.add("[x] ".to_owned()),
+            _ => placeholders.add("[ ] ".to_owned()),
+        }
+    });
+    // Encrypted text can't be read without the passphrase
+    let html = replace_elements(&html, "en-crypt", |_| "[encrypted]".to_owned());
+
+    let markdown = placeholders.fill(&html2md::parse_html(&html));
+    markdown.trim().to_owned() + "\n"
+}
+
+/// Words standing in for markdown while the rest is converted. Each is a
+/// random ULID, checked not to be in the note, followed by a fixed width
+/// number, so they only match themselves.
+struct Placeholders {
+    prefix: String,
+    values: Vec<String>,
+}
+
+impl Placeholders {
+    fn new(text: &str) -> Placeholders {
+        let prefix = std::iter::repeat_with(rusty_ulid::generate_ulid_string)
+            .find(|prefix| !text.contains(prefix.as_str()))
+            .unwrap();
+        Placeholders { prefix, values: vec!() }
+    }
+
+    /// The placeholder for `value`
+    fn add(&mut self, value: String) -> String {
+        self.values.push(value);
+        self.placeholder(self.values.len() - 1)
+    }
+
+    fn placeholder(&self, n: usize) -> String {
+        format!("{}{:08}", self.prefix, n)
+    }
+
+    /// `text` with the placeholders replaced by their values
+    fn fill(&self, text: &str) -> String {
+        let mut out = text.to_owned();
+        for (n, value) in self.values.iter().enumerate() {
+            out = out.replace(&self.placeholder(n), value);
+        }
+        out
+    }
+}
+
+/// `html` with each `<name ...>` element, and its closing tag if any,
+/// replaced by what `replace` returns for its attributes
+fn replace_elements<F: FnMut(&str) -> String>(html: &str, name: &str, mut replace: F) -> String {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut out = String::with_capacity(html.len());
+    let mut offset = 0;
+
+    while let Some(found) = html[offset..].find(&open) {
+        let start = offset + found;
+        let end = match html[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let attributes = html[start + open.len()..end - 1].trim_end_matches('/');
+
+        out.push_str(&html[offset..start]);
+        out.push_str(&replace(attributes));
+        offset = end;
+
+        // Elements with content, rather than self-closing, drop it
+        if !html[..end].ends_with("/>") {
+            if let Some(found) = html[offset..].find(&close) {
+                offset += found + close.len();
+            }
+        }
+    }
+
+    out.push_str(&html[offset..]);
+    out
+}
+
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let start = attributes.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = attributes[start..].find('"')? + start;
+    Some(attributes[start..end].to_owned())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_enex() -> Result<()> {
+        let png = [0x89u8, b'P', b'N', b'G', 0, 1, 2, 3];
+        let hash = format!("{:x}", md5::compute(png));
+        let enex = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE en-export SYSTEM "http://xml.evernote.com/pub/evernote-export3.dtd">
+<en-export export-date="20200101T000000Z" application="Evernote" version="10">
+  <note>
+    <title>Trip &amp; plans</title>
+    <content><![CDATA[<?xml version="1.0" encoding="UTF-8"?><!DOCTYPE en-note SYSTEM "http://xml.evernote.com/pub/enml2.dtd"><en-note><div>Pack <b>boots</b> &amp; map ☐ ☑</div><div><en-todo checked="true"/>book hotel</div><en-media type="image/png" hash="{}"/></en-note>]]></content>
+    <created>20190520T175038Z</created>
+    <updated>20200102T030405Z</updated>
+    <tag>travel</tag>
+    <resource>
+      <data encoding="base64">
+{}
+      </data>
+      <mime>image/png</mime>
+      <resource-attributes><file-name>trip map (v2).png</file-name></resource-attributes>
+    </resource>
+  </note>
+  <note>
+    <title>Packing</title>
+    <content><![CDATA[<en-note><div>socks</div></en-note>]]></content>
+  </note>
+</en-export>"#, hash, base64::encode(png));
+
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("Holidays.enex");
+        std::fs::write(&file, enex)?;
+
+        let mut heap = Heap::init(tmp.path().join("heap"))?;
+        assert_eq!(import(&mut heap, &file)?.imported, 2);
+
+        let card = &heap.find("boots")?[0].card.path;
+        let content = heap.read_card(card)?;
+        let (fm, body) = FrontMatter::parse(&content);
+        let fm = fm.unwrap();
+        assert_eq!(fm.get("title"), Some("Trip & plans"));
+        assert_eq!(fm.tags(), vec!["Holidays", "travel"]);
+        assert_eq!(fm.get("created"), Some("2019-05-20T17:50:38Z"));
+        assert!(body.contains("**boots**"), "{}", body);
+        assert!(body.contains("[x] book hotel"), "{}", body);
+        // Checkboxes written as text are left as they are
+        assert!(body.contains("map \u{2610} \u{2611}"), "{}", body);
+
+        let attachment = format!("attachments/{}/trip map (v2).png", git2::Oid::hash_object(git2::ObjectType::Blob, &png)?);
+        assert!(body.contains(&format!("![trip map (v2).png](<{}>)", attachment)), "{}", body);
+        assert_eq!(std::fs::read(heap.path().join(&attachment))?, png);
+        assert_eq!(heap.find("socks")?.len(), 1);
+
+        Ok(())
+    }
+}
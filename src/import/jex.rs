@@ -0,0 +1,225 @@
+//! Joplin's JEX export: a tar of every item, notebooks and tags included, as
+//! `<id>.md` files, with attachment data under `resources/`. Each item is
+//! its title and body followed by `key: value` metadata, its `type_` saying
+//! what sort of item it is.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{attachment_url, new_card_path, replace_markdown_links, rfc3339, Batch, Summary};
+use crate::frontmatter::FrontMatter;
+use crate::heap::Heap;
+use crate::links;
+
+const NOTE: &str = "1";
+const FOLDER: &str = "2";
+const RESOURCE: &str = "4";
+const TAG: &str = "5";
+const NOTE_TAG: &str = "6";
+
+/// Notes whose body is HTML rather than Markdown
+const HTML_MARKUP: &str = "2";
+
+/// Target of links to notes and resources, followed by the item id
+const ITEM_LINK: &str = ":/";
+
+#[derive(Debug, Default)]
+struct Item {
+    title: String,
+    body: String,
+    meta: HashMap<String, String>,
+}
+
+impl Item {
+    fn parse(text: &str) -> Item {
+        let text = text.trim_end();
+        let (content, meta) = match text.rfind("\n\n") {
+            Some(split) => (&text[..split], &text[split + 2..]),
+            None => ("", text),
+        };
+
+        let meta = meta.lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+            .collect();
+
+        let (title, body) = match content.split_once('\n') {
+            Some((title, body)) => (title, body.strip_prefix('\n').unwrap_or(body)),
+            None => (content, ""),
+        };
+
+        Item { title: title.to_owned(), body: body.to_owned(), meta }
+    }
+
+    fn get(&self, key: &str) -> &str {
+        self.meta.get(key).map(String::as_str).unwrap_or("")
+    }
+}
+
+/// Import the notes in a JEX archive. Notebooks, including their parents,
+/// become tags such as `work/projects`, alongside the notes' own tags.
+pub fn import(heap: &mut Heap, file: &Path) -> Result<Summary> {
+    let archive = std::fs::File::open(file)
+        .with_context(|| format!("Failed to open {}", file.display()))?;
+
+    let mut items: BTreeMap<String, Item> = BTreeMap::new();
+    let mut data: HashMap<String, Vec<u8>> = HashMap::new();
+
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries().with_context(|| format!("{} isn't a Joplin export", file.display()))? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let id = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => continue,
+        };
+
+        let mut bytes = vec!();
+        entry.read_to_end(&mut bytes)?;
+
+        if path.starts_with("resources") {
+            data.insert(id, bytes);
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            items.insert(id, Item::parse(&String::from_utf8_lossy(&bytes)));
+        }
+    }
+
+    let of_type = |kind: &'static str| items.iter().filter(move |(_, item)| item.get("type_") == kind);
+
+    let mut tags: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (_, note_tag) in of_type(NOTE_TAG) {
+        if let Some(tag) = items.get(note_tag.get("tag_id")).filter(|tag| tag.get("type_") == TAG) {
+            tags.entry(note_tag.get("note_id")).or_default().push(&tag.title);
+        }
+    }
+
+    let notes: BTreeMap<&str, PathBuf> = of_type(NOTE).map(|(id, _)| (id.as_str(), new_card_path())).collect();
+
+    let mut batch = Batch::new(heap);
+
+    let mut resources: HashMap<&str, PathBuf> = HashMap::new();
+    for (id, resource) in of_type(RESOURCE) {
+        let bytes = match data.get(id) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let extension = resource.get("file_extension");
+        let name = if resource.title.is_empty() || (!extension.is_empty() && !resource.title.contains('.')) {
+            format!("{}.{}", if resource.title.is_empty() { id } else { &resource.title }, extension)
+        } else {
+            resource.title.clone()
+        };
+        resources.insert(id, batch.attach(&name, bytes)?);
+    }
+
+    for (id, note) in of_type(NOTE) {
+        let mut frontmatter = FrontMatter::default();
+        frontmatter.set("title", &note.title);
+
+        let mut note_tags: Vec<String> = notebook(&items, note.get("parent_id")).into_iter().collect();
+        note_tags.extend(tags.get(id.as_str()).into_iter().flatten().map(|tag| tag.to_string()));
+        if note.get("is_todo") == "1" {
+            note_tags.push("todo".to_owned());
+        }
+        frontmatter.set_tags(&note_tags);
+
+        for (key, joplin_key, fallback) in &[("created", "user_created_time", "created_time"), ("modified", "user_updated_time", "updated_time")] {
+            let date = Some(note.get(joplin_key)).filter(|d| !d.is_empty()).unwrap_or(note.get(fallback));
+            if !date.is_empty() {
+                frontmatter.set(key, &rfc3339(date));
+            }
+        }
+
+        let body = if note.get("markup_language") == HTML_MARKUP {
+            html2md::parse_html(&note.body)
+        } else {
+            note.body.clone()
+        };
+        let body = replace_markdown_links(&body, ITEM_LINK, |id, label| {
+            let id = id.split('#').next().unwrap_or(id);
+            match (notes.get(id), resources.get(id)) {
+                (Some(card), _) => Some(links::render(&links::key(card), Some(label))),
+                (_, Some(attachment)) => Some(format!("[{}]({})", label, attachment_url(attachment))),
+                _ => None,
+            }
+        });
+
+        batch.add(&notes[id.as_str()], frontmatter.render(&body))?;
+    }
+
+    Ok(Summary { imported: batch.commit()?, ..Summary::default() })
+}
+
+/// Path of the notebook `id` from the top level, e.g. `work/projects`
+fn notebook<'a>(items: &'a BTreeMap<String, Item>, mut id: &'a str) -> Option<String> {
+    let mut names = vec!();
+    while let Some(folder) = items.get(id).filter(|item| item.get("type_") == FOLDER) {
+        // Guard against cycles in a damaged export
+        if names.len() > items.len() {
+            break;
+        }
+        names.push(folder.title.as_str());
+        id = folder.get("parent_id");
+    }
+
+    if names.is_empty() {
+        return None;
+    }
+    names.reverse();
+    Some(names.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(archive: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn import_jex() -> Result<()> {
+        let mut archive = tar::Builder::new(vec!());
+        append(&mut archive, "f1.md", b"Work\n\nid: f1\nparent_id: \ntype_: 2")?;
+        append(&mut archive, "f2.md", b"Projects\n\nid: f2\nparent_id: f1\ntype_: 2")?;
+        append(&mut archive, "n1.md", b"Plan\n\nSee ![chart](:/r1) and [the list](:/n2)\n\nid: n1\nparent_id: f2\n\
+            created_time: 2020-01-01T00:00:00.000Z\nuser_created_time: 2019-06-01T12:00:00.000Z\n\
+            user_updated_time: 2020-02-01T12:00:00.000Z\nis_todo: 0\nmarkup_language: 1\ntype_: 1")?;
+        append(&mut archive, "n2.md", b"List\n\n<ul><li>milk</li></ul>\n\nid: n2\nparent_id: f1\nis_todo: 1\nmarkup_language: 2\ntype_: 1")?;
+        append(&mut archive, "t1.md", b"urgent\n\nid: t1\ntype_: 5")?;
+        append(&mut archive, "nt1.md", b"id: nt1\nnote_id: n1\ntag_id: t1\ntype_: 6")?;
+        append(&mut archive, "r1.md", b"chart\n\nid: r1\nmime: image/png\nfile_extension: png\ntype_: 4")?;
+        append(&mut archive, "resources/r1.png", b"\x89PNG")?;
+
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("export.jex");
+        std::fs::write(&file, archive.into_inner()?)?;
+
+        let mut heap = Heap::init(tmp.path().join("heap"))?;
+        assert_eq!(import(&mut heap, &file)?.imported, 2);
+
+        let plan = &heap.find("tags:urgent")?[0].card.path;
+        let content = heap.read_card(plan)?;
+        let (fm, body) = FrontMatter::parse(&content);
+        let fm = fm.unwrap();
+        assert_eq!(fm.get("title"), Some("Plan"));
+        assert_eq!(fm.tags(), vec!["Work/Projects", "urgent"]);
+        assert_eq!(fm.get("created"), Some("2019-06-01T12:00:00Z"));
+        assert!(body.starts_with("See ![chart](<attachments/"), "{}", body);
+        assert!(heap.read_card(plan)?.contains("/chart.png>)"));
+
+        let list = heap.links(plan)?[0].path.clone().unwrap();
+        let list = heap.read_card(&list)?;
+        assert!(list.contains("tags: Work, todo\n"), "{}", list);
+        assert!(list.contains("milk"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! Archives written by `nb export jsonl`. Cards and attachments keep their
+//! paths, so links between them still resolve, which means restoring into a
+//! heap that already has any of them fails.
+
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use super::{Batch, Summary};
+use crate::export::Record;
+use crate::heap::{self, Heap};
+
+/// Restore the archive at `file`, or read it from stdin if `file` is `-`.
+/// Every card and attachment is restored byte for byte. History doesn't
+/// survive; the timestamps are only kept in the archive.
+pub fn import(heap: &mut Heap, file: &Path) -> Result<Summary> {
+    let input: Box<dyn Read> = if file == Path::new("-") {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(std::fs::File::open(file).with_context(|| format!("Failed to open {}", file.display()))?)
+    };
+
+    let mut batch = Batch::new(heap);
+    for (number, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid record on line {}", number + 1))?;
+        let path = Path::new(&record.card.path);
+
+        let data = match (record.data, record.content) {
+            (Some(data), _) => base64::decode(data)
+                .with_context(|| format!("Invalid base64 data on line {}", number + 1))?,
+            (None, Some(content)) => content.into_bytes(),
+            (None, None) => bail!("No content or data on line {}", number + 1),
+        };
+
+        if heap::is_attachment(path) {
+            batch.restore(path, &data)?;
+        } else {
+            batch.add(path, &data)?;
+        }
+    }
+
+    Ok(Summary { imported: batch.commit()?, ..Summary::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut heap = Heap::init(tmp.path().join("old"))?;
+        let cards: &[(&str, &[u8])] = &[
+            ("a.md", b"---\ntitle:  A\ntags: x\nauthor: me\n---\nsee [[dir/b]]\n"),
+            ("dir/b.md", b"no front matter"),
+            ("old.txt", b"caf\xe9 notes from 1999"),
+        ];
+        for (path, content) in cards {
+            heap.write_card(Some(path), content)?;
+        }
+        let attachment = heap.write_attachment("pic.png", &[0, 159, 146, 150])?;
+        let mut paths: Vec<&Path> = cards.iter().map(|(path, _)| Path::new(path)).collect();
+        paths.push(&attachment);
+        heap.commit_paths(&paths)?;
+
+        let mut archive = vec!();
+        assert_eq!(export::jsonl(&heap, &mut archive)?, 4);
+        let lines: Vec<serde_json::Value> = archive.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(lines[0]["id"], "a");
+        assert_eq!(lines[0]["attributes"]["tags"], "x");
+        assert_eq!(lines[0]["links"], serde_json::json!(["dir/b"]));
+        assert!(lines[0]["created"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["path"], attachment.to_string_lossy().as_ref());
+        assert_eq!(lines[3]["path"], "old.txt");
+        assert!(lines[3]["content"].is_null() && lines[3]["data"].is_string());
+
+        let file = tmp.path().join("archive.jsonl");
+        std::fs::write(&file, &archive)?;
+        let mut restored = Heap::init(tmp.path().join("new"))?;
+        assert_eq!(import(&mut restored, &file)?.imported, 3);
+
+        for (path, content) in cards {
+            assert_eq!(&std::fs::read(restored.path().join(path))?, content);
+        }
+        assert_eq!(std::fs::read(restored.path().join(&attachment))?, vec![0, 159, 146, 150]);
+        assert_eq!(restored.backlinks("dir/b.md")?.len(), 1);
+
+        // Restoring over existing cards fails rather than overwriting them
+        assert!(import(&mut restored, &file).is_err());
+
+        Ok(())
+    }
+}
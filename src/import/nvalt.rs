@@ -0,0 +1,92 @@
+//! Notational Velocity and nvALT folders: one file per note, named after its
+//! title, as plain text, Markdown or RTF. Tags aren't part of the file but
+//! kept in extended attributes, which only survive on macOS.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use super::{folder, rtf, Summary};
+use crate::heap::Heap;
+
+const EXTENSIONS: &[&str] = &["txt", "text", "md", "markdown", "mdown", "rtf"];
+
+/// Attributes nvALT stores tags in: its own OpenMeta tags, and Finder tags
+/// which it writes as well from 2.2 on
+const TAG_ATTRIBUTES: &[&str] = &[
+    "com.apple.metadata:kMDItemOMUserTags",
+    "com.apple.metadata:_kMDItemUserTags",
+];
+
+/// Import an nvALT notes folder. Titles come from file names and `[[links]]`
+/// between notes are kept, as they are in `dir`.
+pub fn import(heap: &mut Heap, source: &Path) -> Result<Summary> {
+    folder(heap, source, EXTENSIONS, read, false)
+}
+
+fn read(path: &Path) -> Result<(String, Vec<String>)> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let text = String::from_utf8_lossy(&bytes);
+
+    let is_rtf = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("rtf"));
+    let text = if is_rtf { rtf::to_text(&text) } else { text.into_owned() };
+
+    let mut tags: Vec<String> = vec!();
+    for name in TAG_ATTRIBUTES {
+        // Unsupported outside macOS, where the attributes can't exist anyway
+        if let Ok(Some(value)) = xattr::get(path, name) {
+            for tag in decode_tags(&value) {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+    }
+
+    Ok((text, tags))
+}
+
+/// Tags from a binary property list of strings. Finder tags carry their
+/// color after a newline, e.g. "Work\n6".
+fn decode_tags(plist: &[u8]) -> Vec<String> {
+    plist::from_bytes::<Vec<String>>(plist)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|tag| tag.split('\n').next().map(|tag| tag.trim().to_owned()))
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_folder() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let notes = tmp.path().join("notes");
+        std::fs::create_dir(&notes)?;
+        std::fs::write(notes.join("Groceries.txt"), "eggs, see [[Recipes]]")?;
+        std::fs::write(notes.join("Recipes.rtf"), "{\\rtf1\\ansi{\\fonttbl\\f0 Helvetica;}\\f0 pancakes\\par}")?;
+        std::fs::write(notes.join("Notes & Settings"), "")?;
+
+        let mut heap = Heap::init(tmp.path().join("heap"))?;
+        let summary = import(&mut heap, &notes)?;
+        assert_eq!(summary.imported, 2);
+
+        let recipes = &heap.find("pancakes")?[0].card.path;
+        assert!(heap.read_card(recipes)?.contains("title: Recipes\n"));
+        let groceries = &heap.find("eggs")?[0].card.path;
+        assert_eq!(heap.links(groceries)?[0].path.as_deref(), Some(Path::new(recipes)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn finder_tags() {
+        let mut plist = vec!();
+        plist::to_writer_binary(&mut plist, &vec!["Work\n6", "todo"]).unwrap();
+        assert_eq!(decode_tags(&plist), vec!["Work", "todo"]);
+        assert!(decode_tags(b"garbage").is_empty());
+    }
+}
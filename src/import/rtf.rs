@@ -0,0 +1,168 @@
+//! Plain text from RTF, as written by TextEdit and nvALT. Formatting is
+//! dropped; paragraphs, tabs and special characters are kept.
+
+/// Destinations holding metadata rather than text
+const SKIPPED_DESTINATIONS: &[&str] = &[
+    "fonttbl", "colortbl", "stylesheet", "info", "pict", "header", "footer",
+    "expandedcolortbl", "listtable", "listoverridetable",
+];
+
+#[derive(Clone, Copy)]
+struct Group {
+    skip: bool,     // Inside a destination with no text
+    fallback: usize, // Characters following \uN to skip, set by \ucN
+}
+
+pub fn to_text(rtf: &str) -> String {
+    let mut text = String::new();
+    let mut stack = vec!();
+    let mut group = Group { skip: false, fallback: 1 };
+    let mut skip_chars = 0; // Fallback characters left to skip after \uN
+    let mut chars = rtf.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                stack.push(group);
+                // {\*\destination ...} is ignorable by definition
+                if chars.peek() == Some(&'\\') {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if lookahead.peek() == Some(&'*') {
+                        group.skip = true;
+                    }
+                }
+            }
+            '}' => {
+                group = stack.pop().unwrap_or(group);
+                skip_chars = 0;
+            }
+            '\\' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+
+                if word.is_empty() {
+                    let symbol = match chars.next() {
+                        Some(symbol) => symbol,
+                        None => break,
+                    };
+                    let out = match symbol {
+                        '\'' => {
+                            let hex: String = chars.by_ref().take(2).collect();
+                            u8::from_str_radix(&hex, 16).ok().map(latin1)
+                        }
+                        '\\' | '{' | '}' => Some(symbol),
+                        '~' => Some(' '),
+                        '\n' | '\r' => Some('\n'),
+                        _ => None,
+                    };
+                    if let Some(out) = out {
+                        emit(&mut text, &group, &mut skip_chars, out);
+                    }
+                    continue;
+                }
+
+                let mut param = String::new();
+                if chars.peek() == Some(&'-') {
+                    param.push('-');
+                    chars.next();
+                }
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    param.push(c);
+                    chars.next();
+                }
+                // A single space delimits the control word
+                if chars.peek() == Some(&' ') {
+                    chars.next();
+                }
+                let param: Option<i32> = param.parse().ok();
+
+                if SKIPPED_DESTINATIONS.contains(&word.as_str()) {
+                    group.skip = true;
+                    continue;
+                }
+
+                let out = match word.as_str() {
+                    "par" | "line" | "sect" | "page" => Some('\n'),
+                    "tab" => Some('\t'),
+                    "emdash" => Some('\u{2014}'),
+                    "endash" => Some('\u{2013}'),
+                    "bullet" => Some('\u{2022}'),
+                    "lquote" => Some('\u{2018}'),
+                    "rquote" => Some('\u{2019}'),
+                    "ldblquote" => Some('\u{201C}'),
+                    "rdblquote" => Some('\u{201D}'),
+                    "uc" => {
+                        group.fallback = param.unwrap_or(1).max(0) as usize;
+                        None
+                    }
+                    "u" => {
+                        // Negative values are code units above 32767
+                        let unit = param.unwrap_or(0) as i64;
+                        let unit = if unit < 0 { unit + 65536 } else { unit };
+                        let out = std::char::from_u32(unit as u32);
+                        if let Some(out) = out {
+                            emit(&mut text, &group, &mut skip_chars, out);
+                        }
+                        skip_chars = group.fallback;
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(out) = out {
+                    emit(&mut text, &group, &mut skip_chars, out);
+                }
+            }
+            // Line breaks in the source are insignificant
+            '\n' | '\r' => {}
+            c => emit(&mut text, &group, &mut skip_chars, c),
+        }
+    }
+
+    text
+}
+
+fn emit(text: &mut String, group: &Group, skip_chars: &mut usize, c: char) {
+    if *skip_chars > 0 {
+        *skip_chars -= 1;
+    } else if !group.skip {
+        text.push(c);
+    }
+}
+
+/// Characters from `\'hh` escapes, read as Windows-1252 where it differs
+/// from Latin-1
+fn latin1(byte: u8) -> char {
+    match byte {
+        0x91 => '\u{2018}',
+        0x92 => '\u{2019}',
+        0x93 => '\u{201C}',
+        0x94 => '\u{201D}',
+        0x96 => '\u{2013}',
+        0x97 => '\u{2014}',
+        byte => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn textedit_document() {
+        let rtf = "{\\rtf1\\ansi\\ansicpg1252\\cocoartf2580\n{\\fonttbl\\f0\\fswiss\\fcharset0 Helvetica;}\n\
+            {\\colortbl;\\red255\\green255\\blue255;}\n{\\*\\expandedcolortbl;;}\n\
+            \\pard\\tx566\\pardirnatural\\partightenfactor0\n\n\\f0\\fs24 \\cf0 Caf\\'e9 \\{notes\\}\\\n\
+            second line\\par\n\\b bold\\b0 \\u8364?\\tab end}";
+        assert_eq!(to_text(rtf), "Caf\u{e9} {notes}\nsecond line\nbold\u{20ac}\tend");
+    }
+}
@@ -0,0 +1,123 @@
+//! Simplenote's JSON export, `notes.json` in the archive it emails you.
+//! The first line of each note is its title.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::{new_card_path, replace_markdown_links, rfc3339, Batch, Summary};
+use crate::frontmatter::FrontMatter;
+use crate::heap::Heap;
+use crate::links;
+
+/// Target of links between notes, followed by the note id
+const NOTE_LINK: &str = "simplenote://note/";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Export {
+    active_notes: Vec<Note>,
+    // Trashed notes are left behind
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Note {
+    id: String,
+    content: String,
+    creation_date: Option<String>,
+    last_modified: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    pinned: bool,
+}
+
+pub fn import(heap: &mut Heap, file: &Path) -> Result<Summary> {
+    let json = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let export: Export = serde_json::from_str(&json)
+        .with_context(|| format!("{} isn't a Simplenote export", file.display()))?;
+
+    let cards: BTreeMap<&str, PathBuf> = export.active_notes.iter()
+        .map(|note| (note.id.as_str(), new_card_path()))
+        .collect();
+
+    let mut batch = Batch::new(heap);
+    for note in &export.active_notes {
+        batch.add(&cards[note.id.as_str()], convert(note, &cards))?;
+    }
+
+    Ok(Summary { imported: batch.commit()?, ..Summary::default() })
+}
+
+fn convert(note: &Note, cards: &BTreeMap<&str, PathBuf>) -> String {
+    let content = note.content.replace("\r\n", "\n");
+    let mut frontmatter = FrontMatter::default();
+
+    let title = content.lines()
+        .map(|line| line.trim_start_matches('#').trim())
+        .find(|line| !line.is_empty());
+    if let Some(title) = title {
+        frontmatter.set("title", title);
+    }
+    frontmatter.set_tags(&note.tags);
+    for (key, date) in &[("created", &note.creation_date), ("modified", &note.last_modified)] {
+        if let Some(date) = date {
+            frontmatter.set(key, &rfc3339(date));
+        }
+    }
+    if note.pinned {
+        frontmatter.set("pinned", "true");
+    }
+
+    frontmatter.render(&convert_links(&content, cards))
+}
+
+/// Rewrite `[label](simplenote://note/ID)` links between notes as
+/// `[[links]]` to the imported cards
+fn convert_links(content: &str, cards: &BTreeMap<&str, PathBuf>) -> String {
+    replace_markdown_links(content, NOTE_LINK, |id, label| {
+        cards.get(id).map(|card| links::render(&links::key(card), Some(label)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_export() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("notes.json");
+        std::fs::write(&file, r##"{
+            "activeNotes": [
+                {"id": "a1", "content": "# Groceries\r\neggs, see [recipes](simplenote://note/b2)",
+                 "creationDate": "2019-05-20T17:50:38.123Z", "lastModified": "2020-01-02T03:04:05.000Z",
+                 "tags": ["home", "errands"], "pinned": true, "markdown": true},
+                {"id": "b2", "content": "Recipes\npancakes [gone](simplenote://note/zz)"}
+            ],
+            "trashedNotes": [{"id": "c3", "content": "old"}]
+        }"##)?;
+
+        let mut heap = Heap::init(tmp.path().join("heap"))?;
+        assert_eq!(import(&mut heap, &file)?.imported, 2);
+
+        let groceries = &heap.find("eggs")?[0].card.path;
+        let content = heap.read_card(groceries)?;
+        let (fm, body) = FrontMatter::parse(&content);
+        let fm = fm.unwrap();
+        assert_eq!(fm.get("title"), Some("Groceries"));
+        assert_eq!(fm.tags(), vec!["home", "errands"]);
+        assert_eq!(fm.get("created"), Some("2019-05-20T17:50:38Z"));
+        assert_eq!(fm.get("pinned"), Some("true"));
+        assert!(body.starts_with("# Groceries\neggs, see [["));
+
+        let recipes = heap.links(groceries)?[0].path.clone().unwrap();
+        assert!(heap.read_card(&recipes)?.ends_with("pancakes [gone](simplenote://note/zz)"));
+        assert!(heap.find("old")?.is_empty());
+
+        Ok(())
+    }
+}
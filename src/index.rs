@@ -1,6 +1,6 @@
 use anyhow::Context;
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use tantivy::collector::{TopDocs, DocSetCollector};
+use tantivy::query::{QueryParser, AllQuery, Query, TermQuery};
 use tantivy::schema::*;
 use tantivy::{Score, DocAddress};
 use tantivy::ReloadPolicy;
@@ -10,31 +10,39 @@ use tantivy::UserOperation;
 //use anyhow::anyhow;
 
 use std::path::{PathBuf,Path};
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
 
 use crate::card::Card;
 
 pub struct Index {
-    _index: tantivy::Index,
+    index: tantivy::Index,
     reader: tantivy::IndexReader,
-    writer: tantivy::IndexWriter,
+    writer: Option<tantivy::IndexWriter>, // None when opened read-only
     schema: tantivy::schema::Schema,
     queryparser: tantivy::query::QueryParser,
     transactions: Vec<tantivy::UserOperation>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct QueryResult {
-    card: Card,
-    snippet: String
+    pub card: Card,
+    pub snippet: String,
+    #[serde(default)]
+    pub attachment: Option<String>, // Attachment of `card` that matched, if it wasn't the card itself
 }
 
 pub struct Note {
     schema: tantivy::schema::Schema,
-    pub path: PathBuf,
     doc: tantivy::Document,
 }
 
-const DEFAULT_FIELD_NAME: &str = "body";
+/// Fields searched by queries that don't name one
+const DEFAULT_FIELD_NAMES: &[&str] = &["body", "filename", "attachment_text"];
+
+/// Bump whenever `build_schema` changes. Heaps indexed with a different
+/// version are rebuilt when opened.
+pub const SCHEMA_VERSION: u32 = 4;
 
 impl Note {
 
@@ -44,22 +52,39 @@ impl Note {
         doc.add_text(pathfield, path.to_str().unwrap());
         Note {
             schema,
-            path: path.to_owned(),
             doc
         }
     }
 
     fn add_field(&mut self, name: &str, content: &str) -> &Note {
         let field = self.schema.get_field(name).unwrap();
-        self.doc.add_text(field, &content);
+        self.doc.add_text(field, content);
         self
     }
 
     pub fn body(&mut self, content: &str) -> &Note {
-        println!("here: {:?}", content);
         self.add_field("body", content)
     }
 
+    pub fn tag(&mut self, tag: &str) -> &Note {
+        self.add_field("tags", tag)
+    }
+
+    pub fn link(&mut self, target: &str) -> &Note {
+        self.add_field("links", target)
+    }
+
+    /// Describe an attachment, which has no body
+    pub fn attachment(&mut self, filename: &str, mime: &str) -> &Note {
+        self.add_field("filename", filename);
+        self.add_field("mime", mime)
+    }
+
+    /// Text extracted from an attachment, such as a PDF
+    pub fn attachment_text(&mut self, content: &str) -> &Note {
+        self.add_field("attachment_text", content)
+    }
+
     pub fn document(self) -> Document {
         self.doc
     }
@@ -69,18 +94,38 @@ impl Index {
 
     pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Index> {
         let schema = Self::build_schema()?;
-        let index = tantivy::Index::open_in_dir(dir)?;
-        Self::init(index, schema)
+        let index = tantivy::Index::open_in_dir(dir)
+            .context("Failed to open index, try `nb reindex`")?;
+        Self::init(index, schema, true)
+    }
+
+    /// Open the index for searching only, without taking the writer lock
+    pub fn open_read_only<P: AsRef<Path>>(dir: P) -> anyhow::Result<Index> {
+        let schema = Self::build_schema()?;
+        let index = tantivy::Index::open_in_dir(dir)
+            .context("Failed to open index, try `nb reindex`")?;
+        Self::init(index, schema, false)
     }
 
     pub fn create<P: AsRef<Path>>(dir: P) -> anyhow::Result<Index> {
         let schema = Self::build_schema()?;
         let index = tantivy::Index::create_in_dir(dir, schema.clone())?;
-        Self::init(index, schema)
+        Self::init(index, schema, true)
     }
 
-    fn init(index: tantivy::Index, schema: tantivy::schema::Schema) -> anyhow::Result<Index> {
-        let writer = index.writer(50_000_000)?;
+    /// An index held in memory only, gone when it's dropped
+    pub fn create_in_ram() -> anyhow::Result<Index> {
+        let schema = Self::build_schema()?;
+        let index = tantivy::Index::create_in_ram(schema.clone());
+        Self::init(index, schema, true)
+    }
+
+    fn init(index: tantivy::Index, schema: tantivy::schema::Schema, writable: bool) -> anyhow::Result<Index> {
+        let writer = if writable {
+            Some(index.writer(50_000_000)?)
+        } else {
+            None
+        };
 
         let reader = index
             .reader_builder()
@@ -89,20 +134,20 @@ impl Index {
 
         let queryparser = QueryParser::new(
             schema.clone(),
-            vec![schema.get_field(DEFAULT_FIELD_NAME).unwrap()],
+            DEFAULT_FIELD_NAMES.iter().map(|name| schema.get_field(name).unwrap()).collect(),
             tantivy::tokenizer::TokenizerManager::default());
 
 
         let transactions = vec!();
 
-        return Ok(Index {
-            _index: index,
-            reader: reader,
-            writer: writer,
-            schema: schema,
-            queryparser: queryparser,
+        Ok(Index {
+            index,
+            reader,
+            writer,
+            schema,
+            queryparser,
             transactions
-        });
+        })
     }
 
     pub fn reload(&self) -> anyhow::Result<()> {
@@ -110,27 +155,34 @@ impl Index {
     }
 
     pub fn query(&self, query: &str) -> anyhow::Result<Vec<QueryResult>> {
-        let searcher = self.reader.searcher();
-
         let query = self.queryparser.parse_query(query)?;
+        self.search(&*query, 10)
+    }
+
+    /// Every document with exactly `value` in `field`
+    pub fn query_term(&self, field: &str, value: &str) -> anyhow::Result<Vec<QueryResult>> {
+        let field = self.schema.get_field(field)
+            .with_context(|| format!("failed to find '{}' in schema", field))?;
+        let query = TermQuery::new(Term::from_field_text(field, value), IndexRecordOption::Basic);
+        let limit = (self.reader.searcher().num_docs() as usize).max(1);
+        self.search(&query, limit)
+    }
+
+    fn search(&self, query: &dyn Query, limit: usize) -> anyhow::Result<Vec<QueryResult>> {
+        let searcher = self.reader.searcher();
 
         let body = self.schema.get_field("body")
             .context("failed to find 'body' in schema")?;
         let _path = self.schema.get_field("path")
             .context("failed to find 'path' in schema")?;
 
-        let _snippet_generator = SnippetGenerator::create(&searcher, &*query, body)?;
+        let attachment_text = self.schema.get_field("attachment_text")
+            .context("failed to find 'attachment_text' in schema")?;
 
-        let top_docs: Vec<(Score,DocAddress)> = searcher.search(&query, &TopDocs::with_limit(10))?;
+        let _snippet_generator = SnippetGenerator::create(&searcher, query, body)?;
+        let attachment_snippets = SnippetGenerator::create(&searcher, query, attachment_text)?;
 
-        //for (score, doc_address) in top_docs {
-        //    let doc = searcher.doc(doc_address)?;
-        //    let snippet = snippet_generator.snippet_from_doc(&doc);
-        //    println!("{}", self.schema.to_json(&doc));
-        //    println!("Document score {}:", score);
-        //    println!("path: {}", doc.get_first(path).unwrap().text().unwrap());
-        //    println!("snippet: {}", snippet.fragments());
-        //}
+        let top_docs: Vec<(Score,DocAddress)> = searcher.search(query, &TopDocs::with_limit(limit))?;
 
         let docs: Vec<Document> = top_docs.iter().map(|(_,addr)| searcher.doc(*addr).unwrap()).collect();
 
@@ -140,11 +192,49 @@ impl Index {
                     path: doc.get_first(_path).unwrap().text().unwrap().to_owned(),
                     attributes: std::collections::HashMap::new()
                 },
-                snippet: _snippet_generator.snippet_from_doc(&doc).fragments().to_owned()
+                snippet: Some(_snippet_generator.snippet_from_doc(doc).fragments().to_owned())
+                    .filter(|snippet| !snippet.is_empty())
+                    .unwrap_or_else(|| attachment_snippets.snippet_from_doc(doc).fragments().to_owned()),
+                attachment: None,
             }
         ).collect();
 
-        return Ok(results);
+        Ok(results)
+    }
+
+    /// Every tag in the index, with the number of cards tagged with it
+    pub fn tags(&self) -> anyhow::Result<BTreeMap<String, usize>> {
+        let searcher = self.reader.searcher();
+        let tags = self.schema.get_field("tags")
+            .context("failed to find 'tags' in schema")?;
+
+        let mut counts = BTreeMap::new();
+        for addr in searcher.search(&AllQuery, &DocSetCollector)? {
+            let doc = searcher.doc(addr)?;
+            for tag in doc.get_all(tags).filter_map(|v| v.text()) {
+                *counts.entry(tag.to_owned()).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Path and body of every document in the index
+    pub fn documents(&self) -> anyhow::Result<Vec<(PathBuf, String)>> {
+        let searcher = self.reader.searcher();
+        let path = self.schema.get_field("path")
+            .context("failed to find 'path' in schema")?;
+        let body = self.schema.get_field("body")
+            .context("failed to find 'body' in schema")?;
+
+        let mut docs = vec!();
+        for addr in searcher.search(&AllQuery, &DocSetCollector)? {
+            let doc = searcher.doc(addr)?;
+            let text = |field| doc.get_first(field).and_then(|v| v.text()).unwrap_or("").to_owned();
+            docs.push((PathBuf::from(text(path)), text(body)));
+        }
+
+        Ok(docs)
     }
 
     fn build_schema() -> anyhow::Result<tantivy::schema::Schema> {
@@ -155,10 +245,15 @@ impl Index {
         schema_builder.add_text_field("body", TEXT | STORED);
         schema_builder.add_text_field("mtime", TEXT);
         schema_builder.add_text_field("section", TEXT | STORED);
+        schema_builder.add_text_field("tags", STRING | STORED);
+        schema_builder.add_text_field("links", STRING | STORED);
+        schema_builder.add_text_field("filename", TEXT | STORED);
+        schema_builder.add_text_field("mime", STRING | STORED);
+        schema_builder.add_text_field("attachment_text", TEXT | STORED);
 
         let schema = schema_builder.build();
 
-        return Ok(schema);
+        Ok(schema)
     }
 
     pub fn notebuilder(&self, path: &Path) -> Note {
@@ -166,23 +261,47 @@ impl Index {
     }
 
     pub fn add(&mut self, path: &Path, note: Note) {
-        println!("Adding document {:?}", path);
+        log::debug!("Adding document {:?}", path);
         self.transactions.push(UserOperation::Add(note.document()));
     }
 
     pub fn delete(&mut self, path: &Path) {
-        println!("Deleting document {:?}", path);
+        log::debug!("Deleting document {:?}", path);
         let path_field = self.schema.get_field("path").unwrap();
         let term = Term::from_field_text(path_field, path.to_str().unwrap());
         self.transactions.push(UserOperation::Delete(term));
     }
 
-    pub fn commit(&mut self) -> anyhow::Result<u64> {
+    fn writer(&mut self) -> anyhow::Result<&mut tantivy::IndexWriter> {
+        self.writer.as_mut().context("index is open read-only")
+    }
+
+    /// Remove every document. Takes effect on the next commit.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.transactions.clear();
+        self.writer()?.delete_all_documents().context("Failed to clear index")?;
+        Ok(())
+    }
+
+    /// Commit pending operations. `payload` is stored in the index metadata
+    /// atomically with the operations themselves.
+    pub fn commit(&mut self, payload: Option<&str>) -> anyhow::Result<u64> {
         let transactions = self.transactions.drain(..).collect();
-        self.writer.run(transactions);
-        let res = self.writer.commit().context("Failed to commit")?;
+        let writer = self.writer()?;
+        writer.run(transactions);
+        let mut prepared = writer.prepare_commit().context("Failed to prepare commit")?;
+        if let Some(payload) = payload {
+            prepared.set_payload(payload);
+        }
+        let res = prepared.commit().context("Failed to commit")?;
         Ok(res)
     }
+
+    /// Payload stored by the last commit
+    pub fn payload(&self) -> anyhow::Result<Option<String>> {
+        let metas = self.index.load_metas().context("Failed to load index metadata")?;
+        Ok(metas.payload)
+    }
 }
 
 #[cfg(test)]
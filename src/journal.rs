@@ -0,0 +1,140 @@
+//! `nb today` and `nb journal`: one card per day, at a path made from the
+//! date by the `[journal]` pattern in the heap's config.
+
+use anyhow::{bail, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Duration, Local, NaiveDate};
+use std::path::{Path, PathBuf};
+
+use crate::config::Journal;
+use crate::heap::Heap;
+use crate::template;
+
+/// Dates as given on the command line and filled in for `{{date}}`
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// How far back `journal ls --week` looks, today included
+const WEEK: i64 = 7;
+
+pub fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// `today`, `yesterday` or a date such as 2020-01-31
+pub fn parse_date(date: &str) -> Result<NaiveDate> {
+    match date {
+        "today" => Ok(today()),
+        "yesterday" => Ok(today() - Duration::days(1)),
+        date => NaiveDate::parse_from_str(date, DATE_FORMAT)
+            .with_context(|| format!("Invalid date, expected YYYY-MM-DD: {}", date)),
+    }
+}
+
+/// Path of the entry for `date`, relative to the heap
+pub fn path(journal: &Journal, date: NaiveDate) -> Result<PathBuf> {
+    // Formatting with a bad pattern panics, so check it first
+    if StrftimeItems::new(&journal.path).any(|item| item == Item::Error) {
+        bail!("Invalid journal path pattern: {}", journal.path);
+    }
+    Ok(PathBuf::from(date.format(&journal.path).to_string()))
+}
+
+/// Content of a new entry for `date`
+pub fn content(journal: &Journal, date: NaiveDate) -> Result<String> {
+    template::render(&journal.template, |name| {
+        Ok(Some(date.format(DATE_FORMAT).to_string()).filter(|_| name == "date"))
+    })
+}
+
+/// Open the entry for `date` in the editor, starting it from the template
+/// if it's new, then commit and index it
+pub fn open(heap: &mut Heap, date: NaiveDate) -> Result<PathBuf> {
+    match entry(heap, date)? {
+        (path, None) => {
+            heap.edit_card(&path)?;
+            Ok(path)
+        }
+        (path, Some(content)) => heap.add_card_from(Some(&path), &content),
+    }
+}
+
+/// Path of the entry for `date`, with the content to start it from if it's new
+pub fn entry(heap: &Heap, date: NaiveDate) -> Result<(PathBuf, Option<String>)> {
+    let journal = heap.config()?.journal;
+    let path = path(&journal, date)?;
+
+    if heap.path().join(&path).exists() {
+        Ok((path, None))
+    } else {
+        let content = content(&journal, date)?;
+        Ok((path, Some(content)))
+    }
+}
+
+/// Journal entries, most recent first. With `week`, only those from the
+/// last seven days.
+pub fn entries(heap: &Heap, week: bool) -> Result<Vec<(NaiveDate, PathBuf)>> {
+    let journal = heap.config()?.journal;
+    let since = today() - Duration::days(WEEK - 1);
+
+    let mut entries: Vec<(NaiveDate, PathBuf)> = heap.cards()?.into_iter()
+        .filter_map(|card| Some((date(&journal, &card)?, card)))
+        .filter(|(date, _)| !week || (since..=today()).contains(date))
+        .collect();
+    entries.sort_by(|a, b| b.cmp(a));
+    Ok(entries)
+}
+
+/// Date of the entry at `path`, if it is one
+fn date(journal: &Journal, path: &Path) -> Option<NaiveDate> {
+    let date = NaiveDate::parse_from_str(path.to_str()?, &journal.path).ok()?;
+    // Patterns without the full date parse loosely, so check the round trip
+    if date.format(&journal.path).to_string() == path.to_str()? {
+        Some(date)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_paths() -> Result<()> {
+        let journal = Journal::default();
+        let date = NaiveDate::from_ymd_opt(2020, 1, 31).unwrap();
+
+        let path = path(&journal, date)?;
+        assert_eq!(path, PathBuf::from("journal/2020/2020-01-31.md"));
+        assert_eq!(super::date(&journal, &path), Some(date));
+        assert_eq!(super::date(&journal, Path::new("journal/2020/notes.md")), None);
+        assert!(content(&journal, date)?.contains("title: 2020-01-31\n"));
+
+        let bad = Journal { path: "journal/%Q.md".to_owned(), ..Journal::default() };
+        assert!(super::path(&bad, date).is_err());
+        assert_eq!(parse_date("2020-01-31")?, date);
+        assert!(parse_date("31/01/2020").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        let journal = Journal::default();
+
+        let old = NaiveDate::from_ymd_opt(2020, 1, 31).unwrap();
+        for date in &[old, today(), today() - Duration::days(2)] {
+            heap.create_card(Some(path(&journal, *date)?), &content(&journal, *date)?)?;
+        }
+        heap.create_card(Some("journal/ideas.md"), "not an entry")?;
+
+        let dates: Vec<NaiveDate> = entries(&heap, false)?.into_iter().map(|(date, _)| date).collect();
+        assert_eq!(dates, vec![today(), today() - Duration::days(2), old]);
+        assert_eq!(entries(&heap, true)?.len(), 2);
+
+        Ok(())
+    }
+}
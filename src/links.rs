@@ -0,0 +1,151 @@
+//! `[[wiki links]]` between cards. A link names its target by path relative
+//! to the heap, with the `.md` extension optional, and may carry a label
+//! after a `|`: `[[projects/notewell|notewell]]`.
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::heap::ATTACHMENTS_DIR;
+
+const OPEN: &str = "[[";
+const CLOSE: &str = "]]";
+const EXTENSION: &str = ".md";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub target: String,
+    pub label: Option<String>,
+    pub start: usize, // Byte offset of the opening brackets
+    pub end: usize,   // Byte offset just past the closing brackets
+}
+
+impl Link {
+    /// Key the link is indexed under, comparable with `key` of a card path
+    pub fn key(&self) -> String {
+        normalize(&self.target)
+    }
+}
+
+/// Every link in `content`, in order
+pub fn extract(content: &str) -> Vec<Link> {
+    let mut links = vec!();
+    let mut offset = 0;
+
+    while let Some(open) = content[offset..].find(OPEN) {
+        let start = offset + open;
+        let inner_start = start + OPEN.len();
+
+        let close = match content[inner_start..].find(CLOSE) {
+            Some(close) => inner_start + close,
+            None => break,
+        };
+        let inner = &content[inner_start..close];
+
+        // Links don't span lines; resume after the stray brackets
+        if inner.contains('\n') || inner.contains(OPEN) {
+            offset = inner_start;
+            continue;
+        }
+
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target.trim(), Some(label.trim().to_owned())),
+            None => (inner.trim(), None),
+        };
+
+        if !target.is_empty() {
+            links.push(Link {
+                target: target.to_owned(),
+                label,
+                start,
+                end: close + CLOSE.len(),
+            });
+        }
+
+        offset = close + CLOSE.len();
+    }
+
+    links
+}
+
+/// Source text of a link to `target`
+pub fn render(target: &str, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{}{}|{}{}", OPEN, target, label, CLOSE),
+        None => format!("{}{}{}", OPEN, target, CLOSE),
+    }
+}
+
+/// Attachments referenced by Markdown links or images in `content`, such
+/// as `![plot](<../attachments/2f1c.../plot.png>)`, as paths relative to
+/// the heap
+pub fn attachments(content: &str) -> Vec<PathBuf> {
+    let prefix = format!("{}/", ATTACHMENTS_DIR);
+    let mut paths = vec!();
+
+    for (start, _) in content.match_indices(&prefix) {
+        let before = content[..start].trim_end_matches("../");
+        // Link destinations in angle brackets may contain spaces
+        let end = if before.ends_with('<') {
+            content[start..].find(['>', '\n'])
+        } else if before.ends_with('(') || before.ends_with('"') {
+            content[start..].find(|c: char| c == ')' || c == '"' || c.is_whitespace())
+        } else {
+            continue;
+        };
+
+        let target = &content[start..start + end.unwrap_or(content.len() - start)];
+        let path = PathBuf::from(percent_decode_str(target).decode_utf8_lossy().as_ref());
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    paths
+}
+
+/// Key for the card at `path`, relative to the heap
+pub fn key<P: AsRef<Path>>(path: P) -> String {
+    normalize(&path.as_ref().to_string_lossy())
+}
+
+fn normalize(target: &str) -> String {
+    let target = target.trim().trim_start_matches("./");
+    target.strip_suffix(EXTENSION).unwrap_or(target).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_links() {
+        let links = extract("see [[a.md]] and [[dir/b | bee]], not [[\n]] or [[]]");
+        let keys: Vec<String> = links.iter().map(|l| l.key()).collect();
+        assert_eq!(keys, vec!["a", "dir/b"]);
+        assert_eq!(links[1].label.as_deref(), Some("bee"));
+        assert_eq!(&"see [[a.md]]"[links[0].start..links[0].end], "[[a.md]]");
+    }
+
+    #[test]
+    fn render_round_trips() {
+        let link = &extract(&render("dir/b", Some("bee")))[0];
+        assert_eq!((link.key().as_str(), link.label.as_deref()), ("dir/b", Some("bee")));
+    }
+
+    #[test]
+    fn attachment_references() {
+        let content = "![a](<../attachments/1f/a b.png>) [b](attachments/2e/b%20c.pdf \"t\") <img src=\"attachments/3d/c.gif\"> attachments/4c/d";
+        let paths = attachments(content);
+        assert_eq!(paths, vec![
+            PathBuf::from("attachments/1f/a b.png"),
+            PathBuf::from("attachments/2e/b c.pdf"),
+            PathBuf::from("attachments/3d/c.gif"),
+        ]);
+    }
+
+    #[test]
+    fn keys_match_paths() {
+        assert_eq!(key("dir/b.md"), extract("[[dir/b]]")[0].key());
+    }
+}
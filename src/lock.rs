@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const LOCK_FILE: &str = "lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(thiserror::Error, Debug)]
+pub enum LockError {
+    #[error("heap is busy: {path} is locked by another nb process ({owner})")]
+    Busy { path: PathBuf, owner: String },
+}
+
+/// Exclusive lock held by the process writing to a heap. The OS releases it
+/// if the process dies, so there are no stale locks to clean up.
+#[derive(Debug)]
+pub struct HeapLock {
+    file: File,
+}
+
+impl HeapLock {
+
+    /// Lock the heap whose state lives in `nb_path`, waiting up to `timeout`
+    /// for another process to release it.
+    pub fn acquire<P: AsRef<Path>>(nb_path: P, timeout: Duration) -> Result<HeapLock> {
+        let path = nb_path.as_ref().join(LOCK_FILE);
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+        let deadline = Instant::now() + timeout;
+
+        while file.try_lock_exclusive().is_err() {
+            if Instant::now() >= deadline {
+                let mut owner = String::new();
+                file.read_to_string(&mut owner)?;
+                let owner = match owner.trim() {
+                    "" => "unknown pid".to_owned(),
+                    pid => format!("pid {}", pid),
+                };
+                return Err(LockError::Busy { path, owner }.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(HeapLock { file })
+    }
+}
+
+impl Drop for HeapLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let lock = HeapLock::acquire(dir.path(), Duration::from_secs(0))?;
+        let err = HeapLock::acquire(dir.path(), Duration::from_millis(100)).unwrap_err();
+        assert!(err.downcast_ref::<LockError>().is_some());
+        assert!(err.to_string().contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        HeapLock::acquire(dir.path(), Duration::from_secs(0))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+//! `nb lsp`: a Language Server Protocol server over stdio, so any editor
+//! with an LSP client can complete links and tags, follow links, find
+//! backlinks and search the heap. It serves other nb commands over the
+//! heap's socket while running, as `nb watch` does.
+
+use anyhow::{anyhow, bail, Context, Result};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::heap::Heap;
+use crate::links;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+const REQUEST_FAILED: i64 = -32803;
+
+// Enum values from the LSP specification
+const SYNC_FULL: u32 = 1;
+const COMPLETION_KIND_FILE: u32 = 17;
+const COMPLETION_KIND_KEYWORD: u32 = 14;
+const SYMBOL_KIND_FILE: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+
+/// Lines of the target card shown when hovering over a link
+const HOVER_LINES: usize = 20;
+
+/// Bytes of a path left as they are in a file uri
+const PATH: &AsciiSet = &NON_ALPHANUMERIC.remove(b'/').remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+pub struct LanguageServer {
+    heap: Arc<Mutex<Heap>>, // Shared with the rpc server, which serves other nb commands meanwhile
+    root: PathBuf,          // Where the heap is
+    documents: HashMap<String, String>, // Open documents by uri
+    shutdown: bool,
+}
+
+impl LanguageServer {
+    pub fn new(heap: Arc<Mutex<Heap>>) -> LanguageServer {
+        let root = heap.lock().expect("heap lock poisoned").path().to_owned();
+        LanguageServer { heap, root, documents: HashMap::new(), shutdown: false }
+    }
+
+    fn heap(&self) -> MutexGuard<'_, Heap> {
+        self.heap.lock().expect("heap lock poisoned")
+    }
+
+    /// Serve messages from `input` until the client sends `exit` or closes
+    /// it. Messages that can't be read or handled are answered with an
+    /// error, and the server carries on with the next.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    write_message(&mut output, &error(Value::Null, PARSE_ERROR, &format!("{:#}", e)))?;
+                    continue;
+                }
+            };
+            if message["method"] == "exit" {
+                break;
+            }
+
+            let id = message.get("id").cloned();
+            let replies = match (self.handle(message), id) {
+                (Ok(replies), _) => replies,
+                (Err(e), Some(id)) => vec![error(id, INTERNAL_ERROR, &format!("{:#}", e))],
+                (Err(e), None) => {
+                    log::warn!("failed to handle notification: {:#}", e);
+                    vec!()
+                }
+            };
+            for reply in replies {
+                write_message(&mut output, &reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle one message, returning the messages to send back
+    fn handle(&mut self, message: Value) -> Result<Vec<Value>> {
+        let method = message["method"].as_str().unwrap_or("").to_owned();
+        let params = &message["params"];
+
+        // Requests carry an id and need a response, notifications don't
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(&method, params),
+        };
+
+        if self.shutdown && method != "exit" {
+            return Ok(vec![error(id, REQUEST_FAILED, "server is shutting down")]);
+        }
+
+        let result = match method.as_str() {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": SYNC_FULL, "save": true },
+                    "completionProvider": { "triggerCharacters": ["[", "#"] },
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "workspaceSymbolProvider": true,
+                },
+                "serverInfo": { "name": "notewell" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/completion" => self.completion(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "workspace/symbol" => self.symbols(params),
+            _ => return Ok(vec![error(id, METHOD_NOT_FOUND, &format!("unknown method: {}", method))]),
+        };
+
+        Ok(vec![match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error(id, REQUEST_FAILED, &format!("{:#}", e)),
+        }])
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Result<Vec<Value>> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_owned();
+                self.documents.insert(uri.clone(), text);
+            }
+            "textDocument/didChange" => {
+                // Full sync: the last change holds the whole document
+                if let Some(text) = params["contentChanges"].as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                }
+            }
+            "textDocument/didSave" => {
+                if let Ok(path) = self.card_path(&uri) {
+                    if let Err(e) = self.heap().commit_paths(&[path]) {
+                        log::warn!("failed to commit {}: {:?}", uri, e);
+                    }
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return Ok(vec![self.diagnostics_for(&uri, "")]);
+            }
+            _ => return Ok(vec!()),
+        }
+
+        let text = self.documents.get(&uri).cloned().unwrap_or_default();
+        Ok(vec![self.diagnostics_for(&uri, &text)])
+    }
+
+    /// Warn about links to cards that don't exist
+    fn diagnostics_for(&self, uri: &str, text: &str) -> Value {
+        let diagnostics: Vec<Value> = links::extract(text).iter()
+            .filter(|link| self.heap().resolve_link(&link.key()).is_none())
+            .map(|link| json!({
+                "range": range(text, link.start, link.end),
+                "severity": SEVERITY_WARNING,
+                "source": "notewell",
+                "message": format!("No card named '{}'", link.target),
+            }))
+            .collect();
+
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value> {
+        let (text, offset) = self.cursor(params)?;
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let before = &text[line_start..offset];
+
+        // Inside an unclosed [[link
+        if let Some(open) = before.rfind("[[") {
+            let prefix = &before[open + 2..];
+            if !prefix.contains("]]") {
+                let items: Vec<Value> = self.heap().cards()?.iter()
+                    .map(links::key)
+                    .filter(|key| key.starts_with(prefix))
+                    .map(|key| json!({ "label": key, "kind": COMPLETION_KIND_FILE }))
+                    .collect();
+                return Ok(json!(items));
+            }
+        }
+
+        // A #tag
+        let word_start = before.char_indices().rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        if let Some(prefix) = before[word_start..].strip_prefix('#') {
+            let items: Vec<Value> = self.heap().tags()?.into_iter()
+                .filter(|(tag, _)| tag.starts_with(prefix))
+                .map(|(tag, count)| json!({
+                    "label": tag,
+                    "kind": COMPLETION_KIND_KEYWORD,
+                    "detail": format!("{} cards", count),
+                }))
+                .collect();
+            return Ok(json!(items));
+        }
+
+        Ok(json!([]))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value> {
+        let (text, offset) = self.cursor(params)?;
+
+        Ok(link_at(&text, offset)
+            .and_then(|link| self.heap().resolve_link(&link.key()))
+            .map(|path| json!({
+                "uri": path_to_uri(&self.root.join(path)),
+                "range": range("", 0, 0),
+            }))
+            .unwrap_or(Value::Null))
+    }
+
+    /// Every link to the current card, from the cards that link to it
+    fn references(&self, params: &Value) -> Result<Value> {
+        let path = self.card_path(params["textDocument"]["uri"].as_str().unwrap_or(""))?;
+        let key = links::key(&path);
+
+        let mut locations = vec!();
+        let backlinks = self.heap().backlinks(&path)?;
+        for result in backlinks {
+            let source = PathBuf::from(&result.card.path);
+            let content = self.heap().read_card(&source)?;
+            for link in links::extract(&content).iter().filter(|link| link.key() == key) {
+                locations.push(json!({
+                    "uri": path_to_uri(&self.root.join(&source)),
+                    "range": range(&content, link.start, link.end),
+                }));
+            }
+        }
+
+        Ok(json!(locations))
+    }
+
+    /// Preview the card under a link
+    fn hover(&self, params: &Value) -> Result<Value> {
+        let (text, offset) = self.cursor(params)?;
+
+        let link = match link_at(&text, offset) {
+            Some(link) => link,
+            None => return Ok(Value::Null),
+        };
+
+        let target = self.heap().resolve_link(&link.key());
+        let preview = match target {
+            Some(path) => {
+                let content = self.heap().read_card(&path)?;
+                content.lines().take(HOVER_LINES).collect::<Vec<_>>().join("\n")
+            }
+            None => format!("No card named '{}'", link.target),
+        };
+
+        Ok(json!({
+            "contents": { "kind": "markdown", "value": preview },
+            "range": range(&text, link.start, link.end),
+        }))
+    }
+
+    fn symbols(&self, params: &Value) -> Result<Value> {
+        let query = params["query"].as_str().unwrap_or("").trim();
+        if query.is_empty() {
+            return Ok(json!([]));
+        }
+
+        // Partial queries typed into a symbol picker often don't parse
+        let results = self.heap().find(query).unwrap_or_default();
+
+        let symbols: Vec<Value> = results.iter()
+            .map(|result| json!({
+                "name": result.card.path,
+                "kind": SYMBOL_KIND_FILE,
+                "location": {
+                    "uri": path_to_uri(&self.root.join(&result.card.path)),
+                    "range": range("", 0, 0),
+                },
+            }))
+            .collect();
+
+        Ok(json!(symbols))
+    }
+
+    /// Text of the document a request refers to and the byte offset of its position
+    fn cursor(&self, params: &Value) -> Result<(String, usize)> {
+        let uri = params["textDocument"]["uri"].as_str().context("missing textDocument")?;
+
+        let text = match self.documents.get(uri) {
+            Some(text) => text.clone(),
+            None => self.heap().read_card(self.card_path(uri)?)?,
+        };
+
+        let line = params["position"]["line"].as_u64().context("missing position")? as usize;
+        let character = params["position"]["character"].as_u64().context("missing position")? as usize;
+
+        let offset = offset(&text, line, character);
+        Ok((text, offset))
+    }
+
+    /// Path of a document relative to the heap
+    fn card_path(&self, uri: &str) -> Result<PathBuf> {
+        let path = uri_to_path(uri)?;
+        path.strip_prefix(&self.root)
+            .map(|p| p.to_owned())
+            .with_context(|| format!("{} is not in the heap", path.display()))
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn link_at(text: &str, offset: usize) -> Option<links::Link> {
+    links::extract(text).into_iter()
+        .find(|link| link.start <= offset && offset <= link.end)
+}
+
+/// Byte offset of an LSP position, which counts UTF-16 code units
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line).map(|l| l.len()).sum();
+    let line_text = text[line_start..].split('\n').next().unwrap_or("");
+
+    let mut units = 0;
+    for (i, c) in line_text.char_indices() {
+        if units >= character {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line_text.len()
+}
+
+/// LSP position of a byte offset
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(|c| c.len_utf16()).sum();
+    json!({ "line": line, "character": character })
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    json!({ "start": position(text, start), "end": position(text, end) })
+}
+
+fn path_to_uri(path: &Path) -> String {
+    format!("file://{}", percent_encode(path.as_os_str().as_bytes(), PATH))
+}
+
+fn uri_to_path(uri: &str) -> Result<PathBuf> {
+    let encoded = match uri.strip_prefix("file://") {
+        Some(path) => path,
+        None => bail!("Not a file uri: {}", uri),
+    };
+
+    let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+    Ok(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+/// Read one `Content-Length` framed message, or None at end of input. A
+/// message that isn't JSON, or whose headers don't say how long it is, is
+/// an inner error, as the next one can still be read.
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Result<Value>>> {
+    let mut length = Err(anyhow!("Message without Content-Length"));
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().context("Invalid Content-Length");
+            }
+        }
+    }
+
+    let length = match length {
+        Ok(length) => length,
+        Err(e) => return Ok(Some(Err(e))),
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body).context("Invalid JSON")))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<()> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_count_utf16() {
+        let text = "héllo\n😀 [[a]]";
+        assert_eq!(offset(text, 1, 3), "héllo\n😀 ".len());
+        assert_eq!(position(text, "héllo\n😀 ".len()), json!({ "line": 1, "character": 3 }));
+    }
+
+    #[test]
+    fn uris_round_trip() -> Result<()> {
+        let path = Path::new("/notes/a card.md");
+        assert_eq!(path_to_uri(path), "file:///notes/a%20card.md");
+        assert_eq!(uri_to_path(&path_to_uri(path))?, path);
+
+        let path = Path::new("/notes/caf\u{e9} #1?.md");
+        assert_eq!(path_to_uri(path), "file:///notes/caf%C3%A9%20%231%3F.md");
+        assert_eq!(uri_to_path(&path_to_uri(path))?, path);
+        assert!(uri_to_path("https://example.com/a.md").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn survive_bad_messages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let heap = Heap::init(dir.path().join("heap"))?;
+        let mut server = LanguageServer::new(Arc::new(Mutex::new(heap)));
+
+        let mut input = vec!();
+        for body in &["{not json", r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#, r#"{"method":"exit"}"#] {
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        }
+        write!(input, "Content-Length: many\r\n\r\n")?;
+
+        let mut output = vec!();
+        server.run(&input[..], &mut output)?;
+
+        let mut output = &output[..];
+        let reply = read_message(&mut output)?.unwrap()?;
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+        assert_eq!(reply["id"], Value::Null);
+        let reply = read_message(&mut output)?.unwrap()?;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"], Value::Null);
+        // Nothing after exit is read
+        assert!(read_message(&mut output)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_stdio() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("heap");
+        let mut heap = Heap::init(&root)?;
+        heap.create_card(Some("target.md"), "---\ntags: fruit\n---\napples")?;
+        heap.create_card(Some("source.md"), "see [[target]]")?;
+
+        let uri = path_to_uri(&heap.path().join("new.md"));
+        let target_uri = path_to_uri(&heap.path().join("target.md"));
+        let heap = Arc::new(Mutex::new(heap));
+        let mut server = LanguageServer::new(heap.clone());
+
+        // Other nb commands reach the heap through the rpc server meanwhile
+        let _rpc = crate::rpc::Server::spawn(heap, crate::rpc::socket_path(&root))?;
+        let mut client = crate::rpc::Client::connect(&root).expect("rpc server should be running");
+        client.call("add", json!({ "path": "tapas.md", "content": "olives" }))?;
+
+        let text = "[[target]] [[missing]] [[ta";
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "text": text } },
+        }))?;
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["message"], "No card named 'missing'");
+
+        let request = |method: &str, character: usize| json!({
+            "jsonrpc": "2.0", "id": 1, "method": method,
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 0, "character": character } },
+        });
+
+        let completions = &server.handle(request("textDocument/completion", text.len()))?[0]["result"];
+        let labels: Vec<&Value> = completions.as_array().unwrap().iter().map(|c| &c["label"]).collect();
+        assert_eq!(labels, vec!["tapas", "target"]);
+
+        let definition = &server.handle(request("textDocument/definition", 3))?[0]["result"];
+        assert_eq!(definition["uri"], target_uri.as_str());
+
+        let hover = &server.handle(request("textDocument/hover", 3))?[0]["result"];
+        assert!(hover["contents"]["value"].as_str().unwrap().contains("apples"));
+
+        let references = &server.handle(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "textDocument/references",
+            "params": { "textDocument": { "uri": target_uri }, "position": { "line": 0, "character": 0 } },
+        }))?[0]["result"];
+        assert_eq!(references.as_array().unwrap().len(), 1);
+
+        let symbols = &server.handle(json!({
+            "jsonrpc": "2.0", "id": 3, "method": "workspace/symbol", "params": { "query": "apples" },
+        }))?[0]["result"];
+        assert_eq!(symbols[0]["name"], "target.md");
+
+        // Tags after spaces that aren't ASCII
+        let text = "todo\u{a0}#fr\u{3000}#fr";
+        server.handle(json!({
+            "jsonrpc": "2.0", "method": "textDocument/didChange",
+            "params": { "textDocument": { "uri": uri }, "contentChanges": [{ "text": text }] },
+        }))?;
+        for character in &[7, 11] {
+            let completions = &server.handle(request("textDocument/completion", *character))?[0]["result"];
+            assert_eq!(completions[0]["label"], "fruit");
+        }
+
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 use log::{debug};
-use std::path::{PathBuf};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::io::Read;
 
 use anyhow::{Context, Result};
 
@@ -8,10 +10,26 @@ use clap::{crate_description, crate_authors, crate_version, crate_name};
 
 mod index;
 mod repo;
-//mod config;
+mod config;
 mod heap;
 mod card;
 mod printer;
+mod frontmatter;
+mod merge;
+mod lock;
+mod rpc;
+mod watch;
+mod links;
+mod text;
+mod pdf;
+mod journal;
+mod template;
+mod capture;
+mod saved;
+mod lsp;
+mod http;
+mod export;
+mod import;
 
 //use repo::*;
 use index::*;
@@ -36,8 +54,47 @@ fn arg_parser<'a,'b>() -> clap::App<'a,'b> {
                     .index(1)
                     .multiple(true),
             )
+            .arg(
+                Arg::with_name("NO_SYNC")
+                    .help("search the index as is, without indexing new commits")
+                    .long("no-sync")
+            )
+        )
+        .subcommand(clap::SubCommand::with_name("sync")
+            .about("index changes, optionally pulling from and pushing to a remote")
+            .arg(Arg::with_name("REMOTE")
+                .long("remote")
+                .takes_value(true)
+                .min_values(0)
+                .help("also pull from and push to REMOTE (default: origin)"))
+        )
+        .subcommand(clap::SubCommand::with_name("remote")
+            .about("manage git remotes")
+            .subcommand(clap::SubCommand::with_name("add")
+                .about("add a remote to sync notes with")
+                .arg(Arg::with_name("NAME")
+                    .index(1)
+                    .required(true)
+                    .help("remote name"))
+                .arg(Arg::with_name("URL")
+                    .index(2)
+                    .required(true)
+                    .help("remote url")))
+        )
+        .subcommand(clap::SubCommand::with_name("pull")
+            .about("fetch and merge notes from a remote")
+            .arg(Arg::with_name("REMOTE")
+                .index(1)
+                .default_value("origin")
+                .help("remote name"))
+        )
+        .subcommand(clap::SubCommand::with_name("push")
+            .about("push notes to a remote")
+            .arg(Arg::with_name("REMOTE")
+                .index(1)
+                .default_value("origin")
+                .help("remote name"))
         )
-        .subcommand(clap::SubCommand::with_name("sync"))
         .subcommand(clap::SubCommand::with_name("add")
             .about("add a new note")
             .arg(Arg::with_name("PATH")
@@ -45,12 +102,184 @@ fn arg_parser<'a,'b>() -> clap::App<'a,'b> {
                 .index(1)
                 .required(false)
                 .help("note path"))
+            .arg(Arg::with_name("TEMPLATE")
+                .long("template")
+                .short("t")
+                .takes_value(true)
+                .help("start from templates/TEMPLATE.md"))
+            .arg(Arg::with_name("TITLE")
+                .long("title")
+                .takes_value(true)
+                .requires("TEMPLATE")
+                .help("fills in {{title}} in the template"))
         )
         .subcommand(clap::SubCommand::with_name("edit")
             .about("edit an existing note")
             .arg(Arg::with_name("PATH")
                 .index(1)
                 .help("note path")))
+        .subcommand(clap::SubCommand::with_name("capture")
+            .alias("c")
+            .about("note something down without opening the editor")
+            .arg(Arg::with_name("TEXT")
+                .index(1)
+                .required(true)
+                .multiple(true)
+                .help("text to capture"))
+            .arg(Arg::with_name("NEW")
+                .long("new")
+                .short("n")
+                .help("write a new card instead of appending to the inbox")))
+        .subcommand(clap::SubCommand::with_name("append")
+            .about("add text to the end of a note")
+            .arg(Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("note path"))
+            .arg(Arg::with_name("TEXT")
+                .index(2)
+                .help("text to add, read from stdin if left out or -")))
+        .subcommand(clap::SubCommand::with_name("prepend")
+            .about("add text to the start of a note, below its front matter")
+            .arg(Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("note path"))
+            .arg(Arg::with_name("TEXT")
+                .index(2)
+                .help("text to add, read from stdin if left out or -")))
+        .subcommand(clap::SubCommand::with_name("today")
+            .about("open today's journal entry"))
+        .subcommand(clap::SubCommand::with_name("journal")
+            .about("open the journal entry for a day")
+            .arg(Arg::with_name("DATE")
+                .index(1)
+                .default_value("today")
+                .help("YYYY-MM-DD, today or yesterday"))
+            .subcommand(clap::SubCommand::with_name("ls")
+                .about("list journal entries, most recent first")
+                .arg(Arg::with_name("WEEK")
+                    .long("week")
+                    .help("only entries from the last seven days"))))
+        .subcommand(clap::SubCommand::with_name("attach")
+            .about("attach a file to a note")
+            .arg(Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("note path"))
+            .arg(Arg::with_name("FILE")
+                .index(2)
+                .required(true)
+                .help("file to attach")))
+        .subcommand(clap::SubCommand::with_name("watch")
+            .about("keep the index up to date as notes change")
+            .arg(Arg::with_name("COMMIT")
+                .long("commit")
+                .help("commit notes as they are saved")))
+        .subcommand(clap::SubCommand::with_name("serve")
+            .about("serve JSON-RPC requests from editor plugins on a unix socket")
+            .arg(Arg::with_name("SOCKET")
+                .long("socket")
+                .takes_value(true)
+                .help("socket path (default: .nb/nb.sock in the heap)")))
+        .subcommand(clap::SubCommand::with_name("http")
+            .about("serve a web UI and REST API for browsing and editing notes")
+            .arg(Arg::with_name("BIND")
+                .long("bind")
+                .takes_value(true)
+                .default_value(http::DEFAULT_BIND)
+                .help("address to listen on"))
+            .arg(Arg::with_name("ALLOW_REMOTE")
+                .long("allow-remote")
+                .help("allow binding to addresses other machines can reach")))
+        .subcommand(clap::SubCommand::with_name("export")
+            .about("export notes in other formats")
+            .subcommand(clap::SubCommand::with_name("html")
+                .about("render notes as a static site with tag pages and a search index")
+                .arg(Arg::with_name("DIR")
+                    .index(1)
+                    .required(true)
+                    .help("output directory, replaced if it holds a previous export"))
+                .arg(Arg::with_name("TAG")
+                    .long("tag")
+                    .takes_value(true)
+                    .help("only export notes with this tag")))
+            .subcommand(clap::SubCommand::with_name("jsonl")
+                .about("write every note and attachment as JSON lines")
+                .arg(Arg::with_name("FILE")
+                    .index(1)
+                    .help("output file (default: stdout)"))))
+        .subcommand(clap::SubCommand::with_name("import")
+            .about("import notes from other apps")
+            .subcommand(clap::SubCommand::with_name("dir")
+                .about("import a folder of Markdown notes or an Obsidian vault")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help("folder to import")))
+            .subcommand(clap::SubCommand::with_name("nvalt")
+                .about("import a Notational Velocity or nvALT notes folder")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help("notes folder")))
+            .subcommand(clap::SubCommand::with_name("simplenote")
+                .about("import a Simplenote JSON export")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help("notes.json from the export")))
+            .subcommand(clap::SubCommand::with_name("enex")
+                .about("import an Evernote export")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help(".enex file, named after its notebook")))
+            .subcommand(clap::SubCommand::with_name("jex")
+                .about("import a Joplin export")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help(".jex file")))
+            .subcommand(clap::SubCommand::with_name("jsonl")
+                .about("restore an archive written by export jsonl")
+                .arg(Arg::with_name("PATH")
+                    .index(1)
+                    .required(true)
+                    .help("archive file, or - for stdin"))))
+        .subcommand(clap::SubCommand::with_name("lsp")
+            .about("run a language server on stdin/stdout"))
+        .subcommand(clap::SubCommand::with_name("reindex")
+            .about("rebuild the search index from scratch"))
+        .subcommand(clap::SubCommand::with_name("fsck")
+            .about("check the search index against the notes")
+            .arg(Arg::with_name("FIX")
+                .long("fix")
+                .help("repair any problems found")))
+        .subcommand(clap::SubCommand::with_name("ls")
+            .about("list every card"))
+        .subcommand(clap::SubCommand::with_name("query")
+            .about("saved searches, usable in other searches as saved:NAME")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(clap::SubCommand::with_name("save")
+                .about("save a search, replacing any saved as NAME")
+                .arg(Arg::with_name("NAME")
+                    .index(1)
+                    .required(true)
+                    .help("letters, digits, - and _"))
+                .arg(Arg::with_name("QUERYSTRING")
+                    .index(2)
+                    .required(true)
+                    .help("query string")))
+            .subcommand(clap::SubCommand::with_name("run")
+                .about("run a saved search")
+                .arg(Arg::with_name("NAME")
+                    .index(1)
+                    .required(true)))
+            .subcommand(clap::SubCommand::with_name("ls")
+                .about("list saved searches")))
+        .subcommand(clap::SubCommand::with_name("conflicts")
+            .about("list cards with unresolved merge conflicts"))
         .subcommand(clap::SubCommand::with_name("init")
             .about("create a new notebook at PATH")
             .arg(Arg::with_name("PATH")
@@ -64,7 +293,7 @@ fn heap_path(args: &clap::ArgMatches) -> Result<PathBuf> {
         Ok(PathBuf::from(dir))
     } else {
         std::env::var("NB")
-            .map(|s| PathBuf::from(s))
+            .map(PathBuf::from)
             .or(std::env::current_dir())
             .context("failed to find heap path")
     };
@@ -72,6 +301,25 @@ fn heap_path(args: &clap::ArgMatches) -> Result<PathBuf> {
     path.and_then(|p|p.canonicalize().context("failed to canonicalize path"))
 }
 
+/// Open the journal entry for `date` in the editor. If another process
+/// holds the heap it's edited here and committed there.
+fn open_journal(heap_path: PathBuf, date: chrono::NaiveDate) -> Result<()> {
+    let mut client = match rpc::Client::connect(&heap_path) {
+        Some(client) => client,
+        None => return journal::open(&mut Heap::open(heap_path)?, date).map(|_| ()),
+    };
+
+    let heap = Heap::open_read_only(&heap_path)?;
+    let path = match journal::entry(&heap, date)? {
+        (path, None) => {
+            heap::launch_editor(&heap_path.join(&path))?;
+            path
+        }
+        (path, Some(content)) => heap.edit_new_card(Some(path), &content)?,
+    };
+    client.edit_commit(path)
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
@@ -84,23 +332,222 @@ fn main() -> anyhow::Result<()> {
     }
 
     match (matches.subcommand(), heap_path(&matches)) {
+        (("sync", Some(subargs)), Ok(heap_path)) if !subargs.is_present("REMOTE") => {
+            match rpc::Client::connect(&heap_path) {
+                Some(mut client) => client.sync()?,
+                None => Heap::open(heap_path)?.sync()?,
+            }
+        }
         (("sync", Some(subargs)), Ok(heap_path)) => { 
-            //let path = subargs.value_of("PATH").unwrap();
-            Heap::open(heap_path)?.sync()?;
-        }(("add", Some(subargs)), Ok(heap_path)) => { 
-            //let path = subargs.value_of("PATH").unwrap();
-            Heap::open(heap_path)?.add_card(subargs.value_of("PATH"))?;
+            let remote = subargs.value_of("REMOTE").unwrap_or("origin");
+            let conflicts: Vec<PathBuf> = rpc::call_or_open(heap_path, "sync-remote", json!({ "remote": remote }))?;
+            printer::conflicts(&conflicts);
+        }
+        (("remote", Some(subargs)), Ok(heap_path)) => {
+            match subargs.subcommand() {
+                ("add", Some(addargs)) => {
+                    let name = addargs.value_of("NAME").unwrap();
+                    let url = addargs.value_of("URL").unwrap();
+                    rpc::call_or_open::<_, ()>(heap_path, "add-remote", json!({ "name": name, "url": url }))?;
+                }
+                _ => { println!("{}", subargs.usage()); }
+            }
+        }
+        (("pull", Some(subargs)), Ok(heap_path)) => {
+            let remote = subargs.value_of("REMOTE").unwrap();
+            let conflicts: Vec<PathBuf> = rpc::call_or_open(heap_path, "pull", json!({ "remote": remote }))?;
+            printer::conflicts(&conflicts);
+        }
+        (("push", Some(subargs)), Ok(heap_path)) => {
+            rpc::call_or_open::<_, ()>(heap_path, "push", json!({ "remote": subargs.value_of("REMOTE").unwrap() }))?;
+        }
+        (("add", Some(subargs)), Ok(heap_path)) => { 
+            let (path, title) = (subargs.value_of("PATH"), subargs.value_of("TITLE"));
+            match (rpc::Client::connect(&heap_path), subargs.value_of("TEMPLATE")) {
+                // Edited here, committed by the process holding the heap
+                (Some(mut client), template) => {
+                    let heap = Heap::open_read_only(heap_path)?;
+                    let (path, content) = match template {
+                        Some(name) => template::card(&heap, name, path, title)
+                            .map(|(path, content)| (Some(path), content))?,
+                        None => (path.map(PathBuf::from), String::new()),
+                    };
+                    client.edit_commit(heap.edit_new_card(path, &content)?)?;
+                }
+                (None, Some(name)) => { template::add(&mut Heap::open(heap_path)?, name, path, title)?; }
+                (None, None) => { Heap::open(heap_path)?.add_card(path)?; }
+            }
+        }
+        (("capture", Some(subargs)), Ok(heap_path)) => {
+            let text = subargs.values_of("TEXT").unwrap().collect::<Vec<_>>().join(" ");
+            let new = subargs.is_present("NEW");
+            match rpc::Client::connect(&heap_path) {
+                Some(mut client) => client.capture(&text, new)?,
+                None => capture::capture(&mut Heap::open(heap_path)?, &text, new)?,
+            };
+        }
+        ((command @ "append", Some(subargs)), Ok(heap_path)) | ((command @ "prepend", Some(subargs)), Ok(heap_path)) => {
+            let path = subargs.value_of("PATH").unwrap();
+            let text = match subargs.value_of("TEXT") {
+                Some(text) if text != "-" => text.to_owned(),
+                _ => {
+                    let mut text = String::new();
+                    std::io::stdin().read_to_string(&mut text)?;
+                    text
+                }
+            };
+            let prepend = command == "prepend";
+            match rpc::Client::connect(&heap_path) {
+                Some(mut client) => client.append(path, &text, prepend)?,
+                None if prepend => Heap::open(heap_path)?.prepend_card(path, &text)?,
+                None => Heap::open(heap_path)?.append_card(path, &text)?,
+            }
+        }
+        (("today", Some(_)), Ok(heap_path)) => {
+            open_journal(heap_path, journal::today())?;
+        }
+        (("journal", Some(subargs)), Ok(heap_path)) => match subargs.subcommand() {
+            ("ls", Some(lsargs)) => {
+                let heap = Heap::open_for_search(heap_path, true)?;
+                for (date, path) in journal::entries(&heap, lsargs.is_present("WEEK"))? {
+                    println!("{}  {}", date.format(journal::DATE_FORMAT), path.display());
+                }
+            }
+            _ => {
+                let date = journal::parse_date(subargs.value_of("DATE").unwrap())?;
+                open_journal(heap_path, date)?;
+            }
+        },
+        (("attach", Some(subargs)), Ok(heap_path)) => {
+            let file = subargs.value_of("FILE").unwrap();
+            let file = Path::new(file).canonicalize().with_context(|| format!("Failed to read {}", file))?;
+            let attachment: PathBuf = rpc::call_or_open(heap_path, "attach",
+                json!({ "path": subargs.value_of("PATH").unwrap(), "file": file }))?;
+            println!("{}", attachment.display());
         }
         (("edit", Some(subargs)), Ok(heap_path)) => { 
             let path = subargs.value_of("PATH").unwrap();
-            Heap::open(heap_path)?.edit_card(path)?;
+            match rpc::Client::connect(&heap_path) {
+                Some(mut client) => {
+                    heap::launch_editor(&heap_path.join(path))?;
+                    client.edit_commit(path)?;
+                }
+                None => Heap::open(heap_path)?.edit_card(path)?,
+            }
+        }
+        (("serve", Some(subargs)), Ok(heap_path)) => {
+            let socket = subargs.value_of("SOCKET")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| rpc::socket_path(&heap_path));
+            let heap = std::sync::Arc::new(std::sync::Mutex::new(Heap::open(heap_path)?));
+            rpc::Server::spawn(heap, socket)?.join()?;
+        }
+        (("http", Some(subargs)), Ok(heap_path)) => {
+            http::serve(Heap::open(heap_path)?, subargs.value_of("BIND").unwrap(),
+                subargs.is_present("ALLOW_REMOTE"))?;
+        }
+        (("export", Some(subargs)), Ok(heap_path)) => {
+            match subargs.subcommand() {
+                ("html", Some(htmlargs)) => {
+                    let heap = Heap::open_for_search(heap_path, true)?;
+                    let dir = PathBuf::from(htmlargs.value_of("DIR").unwrap());
+                    let count = export::html(&heap, &dir, htmlargs.value_of("TAG"))?;
+                    println!("exported {} cards to {}", count, dir.display());
+                }
+                ("jsonl", Some(jsonlargs)) => {
+                    let heap = Heap::open_read_only(heap_path)?;
+                    match jsonlargs.value_of("FILE") {
+                        Some(file) => {
+                            let mut out = std::io::BufWriter::new(std::fs::File::create(file)?);
+                            export::jsonl(&heap, &mut out)?;
+                            std::io::Write::flush(&mut out)?;
+                        }
+                        None => { export::jsonl(&heap, &mut std::io::stdout().lock())?; }
+                    }
+                }
+                _ => { println!("{}", subargs.usage()); }
+            }
+        }
+        (("import", Some(subargs)), Ok(heap_path)) => {
+            match subargs.subcommand() {
+                (format, Some(importargs)) => {
+                    let source = importargs.value_of("PATH").unwrap();
+                    // The heap may be held by another process, which can't read our stdin
+                    let stdin = match source {
+                        "-" => {
+                            let mut file = tempfile::NamedTempFile::new()?;
+                            std::io::copy(&mut std::io::stdin(), &mut file)?;
+                            Some(file)
+                        }
+                        _ => None,
+                    };
+                    let source = match &stdin {
+                        Some(file) => file.path().to_owned(),
+                        None => Path::new(source).canonicalize().with_context(|| format!("Failed to read {}", source))?,
+                    };
+                    let summary: import::Summary = rpc::call_or_open(heap_path, "import",
+                        json!({ "format": format, "source": source }))?;
+                    printer::import_summary(&summary)?;
+                }
+                _ => { println!("{}", subargs.usage()); }
+            }
+        }
+        (("lsp", Some(_)), Ok(heap_path)) => {
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            // Other nb commands go through the rpc server while this holds the heap
+            let heap = std::sync::Arc::new(std::sync::Mutex::new(Heap::open(&heap_path)?));
+            let _server = rpc::Server::spawn(heap.clone(), rpc::socket_path(&heap_path))?;
+            lsp::LanguageServer::new(heap).run(stdin.lock(), stdout.lock())?;
+        }
+        (("watch", Some(subargs)), Ok(heap_path)) => {
+            watch::watch(Heap::open(heap_path)?, subargs.is_present("COMMIT"))?;
+        }
+        (("reindex", Some(_)), Ok(heap_path)) => {
+            Heap::reindex(heap_path)?;
+        }
+        (("fsck", Some(subargs)), Ok(heap_path)) => {
+            let report: heap::FsckReport = rpc::call_or_open(heap_path, "fsck", json!({ "fix": subargs.is_present("FIX") }))?;
+            printer::fsck_report(&report)?;
+        }
+        (("ls", Some(_)), Ok(heap_path)) => {
+            for card in Heap::open_for_search(heap_path, true)?.cards()? {
+                println!("{}", card.display());
+            }
+        }
+        (("query", Some(subargs)), Ok(heap_path)) => match subargs.subcommand() {
+            ("save", Some(saveargs)) => {
+                rpc::call_or_open::<_, ()>(heap_path, "save-query",
+                    json!({ "name": saveargs.value_of("NAME").unwrap(), "query": saveargs.value_of("QUERYSTRING").unwrap() }))?;
+            }
+            ("run", Some(runargs)) => {
+                let query = format!("saved:{}", runargs.value_of("NAME").unwrap());
+                let res = match rpc::Client::connect(&heap_path) {
+                    Some(mut client) => client.search(&query)?,
+                    None => Heap::open_for_search(heap_path, true)?.find(&query)?,
+                };
+                printer::list_results(res)?;
+            }
+            _ => {
+                for (name, query) in Heap::open_read_only(heap_path)?.queries()? {
+                    println!("{}\t{}", name, query);
+                }
+            }
+        },
+        (("conflicts", Some(_)), Ok(heap_path)) => {
+            let conflicts = match rpc::Client::connect(&heap_path) {
+                Some(mut client) => client.conflicts()?,
+                None => Heap::open_read_only(heap_path)?.conflicts()?,
+            };
+            printer::list_results(conflicts)?;
         }
         (("search", Some(subargs)), Ok(heap_path)) => {
             let query = subargs.value_of("QUERYSTRING").unwrap();
             debug!("query: {:?}", query);
-            let mut heap = Heap::open(heap_path)?;
-            heap.sync()?;
-            let res = heap.find(query)?;
+            let res = match rpc::Client::connect(&heap_path) {
+                Some(mut client) => client.search(query)?,
+                None => Heap::open_for_search(heap_path, !subargs.is_present("NO_SYNC"))?.find(query)?,
+            };
             printer::list_results(res)?;
         }
         _ => {
@@ -108,7 +555,7 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    return Ok(());
+    Ok(())
 }
 
 #[cfg(test)]
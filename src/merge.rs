@@ -0,0 +1,430 @@
+//! Notes-aware three-way merge, used to resolve conflicts when pulling from a
+//! remote. Front matter is merged attribute by attribute and bodies line by
+//! line. Anything that cannot be merged is kept from both sides behind
+//! conflict markers and the card is tagged `conflict`, so nothing is lost.
+
+use std::ops::Range;
+
+use crate::frontmatter::FrontMatter;
+
+pub const CONFLICT_TAG: &str = "conflict";
+
+const OURS_MARKER: &str = "<<<<<<< ours";
+const SEPARATOR: &str = "=======";
+const THEIRS_MARKER: &str = ">>>>>>> theirs";
+
+/// Cards with `merge: union` in their front matter are append-only logs;
+/// concurrent edits are concatenated rather than marked as conflicts.
+const MERGE_ATTRIBUTE: &str = "merge";
+const UNION: &str = "union";
+
+#[derive(Debug, PartialEq)]
+pub struct Resolution {
+    /// Merged content, or None if the file was deleted on both sides
+    pub content: Option<Vec<u8>>,
+    /// Their version, when it could not be merged into `content` and must be
+    /// kept alongside it (binary files)
+    pub theirs: Option<Vec<u8>>,
+    pub conflicted: bool,
+}
+
+/// Resolve a conflicted file given its ancestor, our and their versions
+pub fn resolve(ancestor: Option<&[u8]>, ours: Option<&[u8]>, theirs: Option<&[u8]>) -> Resolution {
+    fn text(bytes: Option<&[u8]>) -> Option<Result<&str, std::str::Utf8Error>> {
+        bytes.map(std::str::from_utf8)
+    }
+
+    match (text(ancestor), text(ours), text(theirs)) {
+        (_, None, None) => Resolution { content: None, theirs: None, conflicted: false },
+        // Modified on one side and deleted on the other: keep the edit
+        (_, Some(Ok(kept)), None) | (_, None, Some(Ok(kept))) => Resolution {
+            content: Some(tag_conflict(kept).into_bytes()),
+            theirs: None,
+            conflicted: true,
+        },
+        (None, Some(Ok(ours)), Some(Ok(theirs))) => merge_text(None, ours, theirs),
+        (Some(Ok(ancestor)), Some(Ok(ours)), Some(Ok(theirs))) => merge_text(Some(ancestor), ours, theirs),
+        // Binary (or undecodable) content can't be merged
+        _ => Resolution {
+            content: ours.or(theirs).map(|b| b.to_owned()),
+            theirs: ours.and(theirs).map(|b| b.to_owned()),
+            conflicted: true,
+        },
+    }
+}
+
+fn merge_text(ancestor: Option<&str>, ours: &str, theirs: &str) -> Resolution {
+    // Front matter that can't be read is merged as part of the text
+    if [ancestor.unwrap_or(""), ours, theirs].iter().any(|text| has_unreadable_front_matter(text)) {
+        let (merged, conflicted) = merge_lines(ancestor.unwrap_or(""), ours, theirs, false);
+        let content = if conflicted { tag_conflict(&merged) } else { merged };
+        return Resolution { content: Some(content.into_bytes()), theirs: None, conflicted };
+    }
+
+    let (base_fm, base_body) = ancestor.map(FrontMatter::parse).unwrap_or((None, ""));
+    let (our_fm, our_body) = FrontMatter::parse(ours);
+    let (their_fm, their_body) = FrontMatter::parse(theirs);
+
+    let base_fm = base_fm.unwrap_or_default();
+    let our_fm = our_fm.unwrap_or_default();
+    let their_fm = their_fm.unwrap_or_default();
+
+    let (mut fm, fm_conflicted) = merge_front_matter(&base_fm, &our_fm, &their_fm);
+    let union = fm.get(MERGE_ATTRIBUTE) == Some(UNION);
+    let (body, body_conflicted) = merge_lines(base_body, our_body, their_body, union);
+
+    let conflicted = fm_conflicted || body_conflicted;
+    if conflicted {
+        fm.add_tag(CONFLICT_TAG);
+    }
+
+    Resolution {
+        content: Some(fm.render(&body).into_bytes()),
+        theirs: None,
+        conflicted,
+    }
+}
+
+/// `content` tagged `conflict`. Front matter that can't be read is left as
+/// it is, rather than a second block being added above it.
+fn tag_conflict(content: &str) -> String {
+    if has_unreadable_front_matter(content) {
+        return content.to_owned();
+    }
+    let (fm, body) = FrontMatter::parse(content);
+    let mut fm = fm.unwrap_or_default();
+    fm.add_tag(CONFLICT_TAG);
+    fm.render(body)
+}
+
+fn has_unreadable_front_matter(content: &str) -> bool {
+    !FrontMatter::split(content).0.is_empty() && FrontMatter::parse(content).0.is_none()
+}
+
+/// Merge attributes key by key. Tags are merged as sets. When both sides set
+/// an attribute to different values ours wins and theirs is kept as
+/// `<key>.theirs`.
+fn merge_front_matter(base: &FrontMatter, ours: &FrontMatter, theirs: &FrontMatter) -> (FrontMatter, bool) {
+    let mut merged = FrontMatter::default();
+    let mut conflicted = false;
+
+    let keys = ours.attributes()
+        .chain(theirs.attributes())
+        .map(|(k, _)| k);
+
+    for key in keys {
+        if key == "tags" || merged.get(key).is_some() {
+            continue;
+        }
+
+        match (base.get(key), ours.get(key), theirs.get(key)) {
+            (_, Some(o), Some(t)) if o == t => merged.set(key, o),
+            (b, Some(o), t) if b == t => merged.set(key, o),
+            (b, o, Some(t)) if b == o => merged.set(key, t),
+            // Removed on one side and left alone on the other
+            (Some(b), Some(o), None) if b == o => {}
+            (Some(b), None, Some(t)) if b == t => {}
+            (_, Some(o), Some(t)) => {
+                merged.set(key, o);
+                merged.set(&format!("{}.theirs", key), t);
+                conflicted = true;
+            }
+            // Edited on one side, removed on the other: keep the edit
+            (_, Some(v), None) | (_, None, Some(v)) => {
+                merged.set(key, v);
+                conflicted = true;
+            }
+            (_, None, None) => {}
+        }
+    }
+
+    let base_tags = base.tags();
+    let our_tags = ours.tags();
+    let their_tags = theirs.tags();
+
+    // A tag survives unless one side removed it and the other left it alone
+    let tags: Vec<&String> = our_tags.iter()
+        .chain(their_tags.iter().filter(|t| !our_tags.contains(t)))
+        .filter(|t| !base_tags.contains(t) || (our_tags.contains(t) && their_tags.contains(t)))
+        .collect();
+    merged.set_tags(&tags);
+
+    (merged, conflicted)
+}
+
+/// Line based three-way merge. Returns the merged text and whether it
+/// contains conflict markers.
+fn merge_lines(base: &str, ours: &str, theirs: &str, union: bool) -> (String, bool) {
+    if ours == theirs || theirs == base {
+        return (ours.to_owned(), false);
+    }
+    if ours == base {
+        return (theirs.to_owned(), false);
+    }
+
+    // Both sides only appended: keep both additions
+    if let (Some(our_tail), Some(their_tail)) = (ours.strip_prefix(base), theirs.strip_prefix(base)) {
+        if base.is_empty() || base.ends_with('\n') {
+            return (format!("{}{}{}", base, ensure_newline(our_tail), their_tail), false);
+        }
+    }
+
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let our_lines: Vec<&str> = ours.split_inclusive('\n').collect();
+    let their_lines: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    let mut out = String::new();
+    let mut conflicted = false;
+
+    for chunk in diff3(&base_lines, &our_lines, &their_lines) {
+        match chunk {
+            Chunk::Stable(lines) => lines.iter().for_each(|l| out.push_str(l)),
+            Chunk::Changed { base, ours, theirs } => {
+                if ours == theirs || theirs == base {
+                    ours.iter().for_each(|l| out.push_str(l));
+                } else if ours == base {
+                    theirs.iter().for_each(|l| out.push_str(l));
+                } else if union {
+                    push_lines(&mut out, &ours);
+                    push_lines(&mut out, &theirs);
+                } else {
+                    conflicted = true;
+                    push_line(&mut out, OURS_MARKER);
+                    push_lines(&mut out, &ours);
+                    push_line(&mut out, SEPARATOR);
+                    push_lines(&mut out, &theirs);
+                    push_line(&mut out, THEIRS_MARKER);
+                }
+            }
+        }
+    }
+
+    (out, conflicted)
+}
+
+fn ensure_newline(s: &str) -> String {
+    if s.is_empty() || s.ends_with('\n') {
+        s.to_owned()
+    } else {
+        format!("{}\n", s)
+    }
+}
+
+fn push_line(out: &mut String, line: &str) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+fn push_lines(out: &mut String, lines: &[&str]) {
+    for line in lines {
+        push_line(out, line.trim_end_matches('\n'));
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Chunk<'a> {
+    Stable(Vec<&'a str>),
+    Changed { base: Vec<&'a str>, ours: Vec<&'a str>, theirs: Vec<&'a str> },
+}
+
+/// Split three versions into chunks that are unchanged on both sides and
+/// chunks that changed on at least one side.
+fn diff3<'a>(base: &[&'a str], ours: &[&'a str], theirs: &[&'a str]) -> Vec<Chunk<'a>> {
+    let our_matches = matches(base, ours);
+    let their_matches = matches(base, theirs);
+
+    let mut chunks = vec!();
+    let (mut b, mut o, mut t) = (0, 0, 0);
+
+    loop {
+        // Find the next base line that both sides kept
+        let next = (b..base.len()).find_map(|i| {
+            match (our_matches[i], their_matches[i]) {
+                (Some(oi), Some(ti)) if oi >= o && ti >= t => Some((i, oi, ti)),
+                _ => None,
+            }
+        });
+
+        let (nb, no, nt) = next.unwrap_or((base.len(), ours.len(), theirs.len()));
+
+        if nb > b || no > o || nt > t {
+            chunks.push(Chunk::Changed {
+                base: base[b..nb].to_vec(),
+                ours: ours[o..no].to_vec(),
+                theirs: theirs[t..nt].to_vec(),
+            });
+        }
+
+        if next.is_none() {
+            break;
+        }
+
+        // Extend the stable run as far as all three agree
+        let mut stable = vec!();
+        let (mut i, mut oi, mut ti) = (nb, no, nt);
+        while i < base.len() && our_matches[i] == Some(oi) && their_matches[i] == Some(ti) {
+            stable.push(base[i]);
+            i += 1;
+            oi += 1;
+            ti += 1;
+        }
+        chunks.push(Chunk::Stable(stable));
+
+        b = i;
+        o = oi;
+        t = ti;
+    }
+
+    chunks
+}
+
+/// For each line of `a`, the index of the line it is matched with in `b`,
+/// taken from git's diff of the two, which needs only linear space
+fn matches(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; a.len()];
+    let hunks = match hunks(&a.concat(), &b.concat()) {
+        Ok(hunks) => hunks,
+        Err(e) => {
+            log::warn!("failed to diff, treating every line as changed: {}", e);
+            return matches;
+        }
+    };
+
+    // Lines between the hunks are the same on both sides
+    let (mut i, mut j) = (0, 0);
+    for (old, new) in hunks.into_iter().chain(std::iter::once((a.len()..a.len(), b.len()..b.len()))) {
+        while i < old.start && j < new.start {
+            matches[i] = Some(j);
+            i += 1;
+            j += 1;
+        }
+        i = old.end;
+        j = new.end;
+    }
+
+    matches
+}
+
+/// Lines of the old and the new text that differ
+type Hunk = (Range<usize>, Range<usize>);
+
+/// Where `a` and `b` differ, in order
+fn hunks(a: &str, b: &str) -> Result<Vec<Hunk>, git2::Error> {
+    let mut options = git2::DiffOptions::new();
+    options.context_lines(0).interhunk_lines(0).force_text(true);
+    let patch = git2::Patch::from_buffers(a.as_bytes(), None, b.as_bytes(), None, Some(&mut options))?;
+
+    (0..patch.num_hunks())
+        .map(|n| {
+            let (hunk, _) = patch.hunk(n)?;
+            Ok((lines(hunk.old_start(), hunk.old_lines()), lines(hunk.new_start(), hunk.new_lines())))
+        })
+        .collect()
+}
+
+/// Indexes of the lines in one side of a hunk. Its start counts from 1,
+/// and for a hunk with no lines on that side is the line before it.
+fn lines(start: u32, count: u32) -> Range<usize> {
+    let start = if count == 0 { start } else { start - 1 } as usize;
+    start..start + count as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+        let res = resolve(Some(base.as_bytes()), Some(ours.as_bytes()), Some(theirs.as_bytes()));
+        (String::from_utf8(res.content.unwrap()).unwrap(), res.conflicted)
+    }
+
+    #[test]
+    fn appends_are_unioned() {
+        let (merged, conflicted) = merge("a\n", "a\nb\n", "a\nc\n");
+        assert_eq!(merged, "a\nb\nc\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn separate_edits_merge_cleanly() {
+        let (merged, conflicted) = merge("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n");
+        assert_eq!(merged, "A\nb\nC\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn front_matter_merges_by_attribute() {
+        let base = "---\ntitle: t\ntags: a, b\n---\nbody\n";
+        let ours = "---\ntitle: t\ntags: a, b, c\nstatus: done\n---\nbody\n";
+        let theirs = "---\ntitle: renamed\ntags: b\n---\nbody\n";
+        let (merged, conflicted) = merge(base, ours, theirs);
+        assert_eq!(merged, "---\ntitle: renamed\nstatus: done\ntags: b, c\n---\nbody\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn removed_attributes_stay_removed() {
+        let base = "---\ntitle: t\nstatus: draft\n---\nbody\n";
+        let (merged, conflicted) = merge(base, "---\ntitle: t\n---\nbody\n", base);
+        assert_eq!(merged, "---\ntitle: t\n---\nbody\n");
+        assert!(!conflicted);
+
+        let (merged, conflicted) = merge(base, "---\ntitle: t\nstatus: draft\n---\nbody, edited\n", "---\nstatus: draft\n---\nbody\n");
+        assert_eq!(merged, "---\nstatus: draft\n---\nbody, edited\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn real_conflicts_keep_both_sides() {
+        let (merged, conflicted) = merge("---\ntags: x\n---\na\nb\n", "---\ntags: x\n---\na\nours\n", "---\ntags: x\n---\na\ntheirs\n");
+        assert_eq!(merged, "---\ntags: x, conflict\n---\na\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n");
+        assert!(conflicted);
+    }
+
+    #[test]
+    fn unreadable_front_matter_merges_as_text() {
+        let base = "---\ntitle: t\n---\nbody\n";
+        let (merged, conflicted) = merge(base, "---\ntitle: t\n---\nours\n", "---\n- a list\n---\ntheirs\n");
+        assert_eq!(merged, "---\n- a list\n---\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n");
+        assert!(conflicted);
+
+        let (merged, conflicted) = merge(base, "---\ntitle: t\n---\nbody\nmore\n", "---\n- a list\n---\nbody\n");
+        assert_eq!(merged, "---\n- a list\n---\nbody\nmore\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn long_texts_merge_line_by_line() {
+        let base: String = (0..20_000).map(|n| format!("line {}\n", n)).collect();
+        let ours = base.replacen("line 10\n", "line ten\n", 1);
+        let theirs = base.replacen("line 19990\n", "line 19990\nline 19990 and a half\n", 1);
+        let (merged, conflicted) = merge(&base, &ours, &theirs);
+        assert_eq!(merged, ours.replacen("line 19990\n", "line 19990\nline 19990 and a half\n", 1));
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn union_cards_never_conflict() {
+        let (merged, conflicted) = merge("---\nmerge: union\n---\na\n", "---\nmerge: union\n---\nb\n", "---\nmerge: union\n---\nc\n");
+        assert_eq!(merged, "---\nmerge: union\n---\nb\nc\n");
+        assert!(!conflicted);
+    }
+
+    #[test]
+    fn edit_beats_delete() {
+        let res = resolve(Some(b"a"), None, Some(b"a, edited"));
+        assert_eq!(res.content, Some(b"---\ntags: conflict\n---\na, edited".to_vec()));
+        assert!(res.conflicted);
+    }
+
+    #[test]
+    fn binary_keeps_both() {
+        let res = resolve(None, Some(&[0xff, 0x00]), Some(&[0xfe, 0x00]));
+        assert_eq!(res.content, Some(vec![0xff, 0x00]));
+        assert_eq!(res.theirs, Some(vec![0xfe, 0x00]));
+        assert!(res.conflicted);
+    }
+}
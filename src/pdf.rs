@@ -0,0 +1,12 @@
+//! Text of PDF attachments, for the index. pdf-extract panics on some
+//! malformed files, so its panics are caught and reported as errors. What
+//! it makes of fonts it can't map goes to the log, not stdout, so it stays
+//! out of search results and `nb lsp`'s protocol.
+
+use anyhow::{anyhow, Result};
+
+pub fn text(data: &[u8]) -> Result<String> {
+    std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(data))
+        .map_err(|_| anyhow!("malformed PDF"))?
+        .map_err(|e| anyhow!("{:?}", e))
+}
@@ -1,7 +1,12 @@
 use anyhow::Result;
-use tantivy::Document;
 
 use crate::index::QueryResult;
+use crate::heap::FsckReport;
+use crate::import;
+use std::path::PathBuf;
+
+/// Only report progress for operations at least this big
+const PROGRESS_THRESHOLD: usize = 100;
 
 pub fn list_results(docs: Vec<QueryResult>) -> Result<()> {
     for doc in docs {
@@ -9,4 +14,70 @@ pub fn list_results(docs: Vec<QueryResult>) -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Report progress of long running operations on stderr
+pub fn progress(label: &str, done: usize, total: usize) {
+    if total < PROGRESS_THRESHOLD {
+        return;
+    }
+
+    if done.is_multiple_of(PROGRESS_THRESHOLD) || done == total {
+        eprint!("\r{} {}/{}", label, done, total);
+    }
+
+    if done == total {
+        eprintln!();
+    }
+}
+
+/// Summarise the files sync skipped as binary on stderr
+pub fn skipped(paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+
+    eprintln!("skipped {} binary files:", paths.len());
+    for path in paths {
+        eprintln!("  {}", path.display());
+    }
+}
+
+/// Cards a pull merged with conflicts, keeping both versions
+pub fn conflicts(paths: &[PathBuf]) {
+    for path in paths {
+        println!("conflict: {} (both versions kept)", path.display());
+    }
+}
+
+pub fn fsck_report(report: &FsckReport) -> Result<()> {
+    let sections = [
+        ("missing", &report.missing),
+        ("stale", &report.stale),
+        ("duplicated", &report.duplicated),
+        ("orphaned", &report.orphaned),
+    ];
+
+    for (label, paths) in sections.iter() {
+        for path in paths.iter() {
+            println!("{}: {}", label, path.display());
+        }
+    }
+
+    println!("db: ok ({} entries)", report.db_entries);
+
+    if report.is_clean() {
+        println!("index: ok");
+    }
+
+    Ok(())
+}
+
+pub fn import_summary(summary: &import::Summary) -> Result<()> {
+    for path in &summary.skipped {
+        println!("skipped: {}", path.display());
+    }
+
+    println!("imported {} cards", summary.imported);
+    Ok(())
+}
@@ -1,16 +1,21 @@
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 use std::path::{Path,PathBuf};
+use std::collections::HashMap;
 
 use std::io::{Write};
 
-use git2::{Object, Repository, Delta, Commit};
-use git2;
+use git2::{Object, Repository, Delta, Commit, Signature, Oid};
+use git2::{FetchOptions, PushOptions, RemoteCallbacks, Cred, CredentialType};
+use git2::build::CheckoutBuilder;
 
 pub struct Repo {
     repo: Repository,
 }
 
-const NB_SUBDIR: &'static str = ".nb";
+const NB_SUBDIR: &str = ".nb";
+
+/// Bits of `IndexEntry::flags` holding the conflict stage
+const INDEX_STAGE_MASK: u16 = 0x3000;
 
 impl<'repo> Repo {
 
@@ -32,7 +37,7 @@ impl<'repo> Repo {
         
         let gitignore = Path::new(".gitignore");
 
-        let mut output = std::fs::File::create(&workdir.join(gitignore))?;
+        let mut output = std::fs::File::create(workdir.join(gitignore))?;
         write!(output, "{}", NB_SUBDIR)?;
 
         this.commit_paths(&[&gitignore])?;
@@ -42,9 +47,16 @@ impl<'repo> Repo {
 
     pub fn commit_paths<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<()> {
         let mut index = self.repo.index()?;
+        let workdir = self.repo.workdir()
+            .context("Could not obtain work directory")?
+            .to_owned();
 
         for path in paths {
-            index.add_path(path.as_ref())?;
+            if workdir.join(path.as_ref()).exists() {
+                index.add_path(path.as_ref())?;
+            } else {
+                index.remove_path(path.as_ref())?;
+            }
         }
 
         let tree_id = index.write_tree()?;
@@ -56,17 +68,24 @@ impl<'repo> Repo {
 
         let head = self.head();
 
-        if head.is_ok() {
-            parents.push(head.as_ref().unwrap());
+        if let Ok(head) = &head {
+            if head.tree_id() == tree_id {
+                // Nothing changed
+                return Ok(());
+            }
+        }
+
+        if let Ok(head) = &head {
+            parents.push(head);
         }
 
-        let signature = self.repo.signature()?;
+        let signature = self.signature()?;
 
         self.repo.commit(
             Some("HEAD"),
             &signature,
             &signature,
-            &"auto",
+            "auto",
             &tree,
             &parents
         )?;
@@ -81,7 +100,7 @@ impl<'repo> Repo {
     pub fn diff(&self, old: Option<&String>, new: Option<&String>) -> Result<Vec<(Delta, PathBuf)>> {
 
         let old = if let Some(old) = old {
-            Some(self.resolve(&old)?.peel_to_commit()?.tree()?)
+            Some(self.resolve(old)?.peel_to_commit()?.tree()?)
         } else {
             None
         };
@@ -89,7 +108,7 @@ impl<'repo> Repo {
         log::debug!("old_commit: {:?}", old);
 
         let new = if let Some(new) = new {
-            Some(self.resolve(&new)?.peel_to_commit()?.tree()?)
+            Some(self.resolve(new)?.peel_to_commit()?.tree()?)
         } else {
             Some(self.head()?.tree()?)
         };
@@ -97,19 +116,304 @@ impl<'repo> Repo {
         log::debug!("new_commit: {:?}", old);
 
         // If no head, we have nothing to index
-        let diff = self.repo.diff_tree_to_tree(old.as_ref(), new.as_ref(), None)?;
+        let mut diff = self.repo.diff_tree_to_tree(old.as_ref(), new.as_ref(), None)?;
+        diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
 
-        Ok(diff.deltas().map(|delta| {
+        Ok(diff.deltas().flat_map(|delta| {
             let path = delta.new_file().path().unwrap().to_owned();
-            (delta.status(), path)
+            match delta.status() {
+                // Reported as the old path going and the new one arriving
+                Delta::Renamed => vec![
+                    (Delta::Deleted, delta.old_file().path().unwrap().to_owned()),
+                    (Delta::Added, path),
+                ],
+                status => vec![(status, path)],
+            }
         }).collect())
     }
 
+    /// Whether `path` is excluded by .gitignore
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.repo.is_path_ignored(path.as_ref()).unwrap_or(false)
+    }
+
+    /// Files added, changed or removed in the worktree since HEAD, leaving
+    /// out ignored files
+    pub fn changed_files(&self) -> Result<Vec<PathBuf>> {
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(false);
+
+        Ok(self.repo.statuses(Some(&mut options))?.iter()
+            .filter_map(|entry| entry.path().map(PathBuf::from))
+            .collect())
+    }
+
+    /// Every file in the HEAD tree
+    pub fn list_files(&self) -> Result<Vec<PathBuf>> {
+        let tree = self.head()?.tree()?;
+        let mut files = vec!();
+
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                if let Some(name) = entry.name() {
+                    files.push(Path::new(dir).join(name));
+                }
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+
+        Ok(files)
+    }
+
+    /// When each file in history was first added and last changed, as
+    /// seconds since the epoch, from one walk over every commit
+    pub fn history(&self) -> Result<HashMap<PathBuf, (i64, i64)>> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push(self.head()?.id())?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        let mut times: HashMap<PathBuf, (i64, i64)> = HashMap::new();
+        for id in walk {
+            let commit = self.repo.find_commit(id?)?;
+            let time = commit.time().seconds();
+            let parent = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+
+            let diff = self.repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+            for delta in diff.deltas() {
+                if let Some(path) = delta.new_file().path() {
+                    let entry = times.entry(path.to_owned()).or_insert((time, time));
+                    entry.1 = time;
+                }
+            }
+        }
+
+        Ok(times)
+    }
+
+    /// Contents of `path` in the HEAD tree
+    pub fn read_head<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let tree = self.head()?.tree()?;
+        let entry = tree.get_path(path.as_ref())
+            .with_context(|| format!("{} is not in HEAD", path.as_ref().display()))?;
+        let blob = self.repo.find_blob(entry.id())?;
+        Ok(blob.content().to_owned())
+    }
+
     pub fn head(&'repo self) -> anyhow::Result<Commit<'repo>> {
         self.repo.head()
             .and_then(|h| h.peel_to_commit())
             .context("Failed to find HEAD")
     }
+
+    /// Name of the branch HEAD points at, e.g. "master"
+    pub fn branch(&self) -> Result<String> {
+        let head = self.repo.head().context("Failed to find HEAD")?;
+        head.shorthand()
+            .map(|s| s.to_owned())
+            .context("HEAD is not a valid branch name")
+    }
+
+    pub fn add_remote(&self, name: &str, url: &str) -> Result<()> {
+        self.repo.remote(name, url)
+            .with_context(|| format!("Failed to add remote {} ({})", name, url))?;
+        Ok(())
+    }
+
+    pub fn fetch(&self, remote: &str) -> Result<()> {
+        let mut remote = self.repo.find_remote(remote)
+            .with_context(|| format!("Failed to find remote: {}", remote))?;
+
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(callbacks());
+
+        // An empty refspec list falls back to the remote's configured refspecs
+        remote.fetch::<&str>(&[], Some(&mut options), None)
+            .with_context(|| format!("Failed to fetch from {}", remote.name().unwrap_or("remote")))
+    }
+
+    /// Merge the remote tracking branch for the current branch into HEAD.
+    /// Must be preceded by a `fetch`. Conflicting files are passed to `resolve`.
+    pub fn merge(&mut self, remote: &str, resolve: Resolver) -> Result<Merge> {
+        let branch = self.branch()?;
+        let tracking = format!("refs/remotes/{}/{}", remote, branch);
+
+        let theirs = match self.repo.find_reference(&tracking) {
+            Ok(reference) => reference,
+            // Nothing has been pushed to the remote yet
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(Merge::UpToDate),
+            Err(e) => return Err(e).context(format!("Failed to resolve {}", tracking)),
+        };
+
+        let annotated = self.repo.reference_to_annotated_commit(&theirs)?;
+        let (analysis, _) = self.repo.merge_analysis(&[&annotated])?;
+
+        if analysis.is_up_to_date() {
+            Ok(Merge::UpToDate)
+        } else if analysis.is_fast_forward() || analysis.is_unborn() {
+            let target = annotated.id();
+            let refname = format!("refs/heads/{}", branch);
+            // Check out before moving the branch so the old HEAD is the checkout baseline
+            self.repo.checkout_tree(&self.repo.find_object(target, None)?, Some(CheckoutBuilder::new().safe()))
+                .context("Failed to check out merged tree")?;
+            self.repo.reference(&refname, target, true, &format!("notewell: fast-forward to {}", tracking))?;
+            self.repo.set_head(&refname)?;
+            Ok(Merge::FastForward(target))
+        } else if analysis.is_normal() {
+            let ours = self.head()?;
+            let theirs = self.repo.find_commit(annotated.id())?;
+
+            let mut index = self.repo.merge_commits(&ours, &theirs, None)?;
+
+            let conflicts = if index.has_conflicts() {
+                self.resolve_conflicts(&mut index, resolve)?
+            } else {
+                vec!()
+            };
+
+            let tree_id = index.write_tree_to(&self.repo)?;
+            let tree = self.repo.find_tree(tree_id)?;
+            let signature = self.signature()?;
+
+            self.repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))
+                .context("Failed to check out merged tree")?;
+
+            let oid = self.repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                &format!("Merge {}", tracking),
+                &tree,
+                &[&ours, &theirs]
+            )?;
+
+            Ok(Merge::Merged { commit: oid, conflicts })
+        } else {
+            bail!("Unable to merge {} into {}", tracking, branch)
+        }
+    }
+
+    /// Replace each conflict in `index` with the output of `resolve`,
+    /// returning the paths that were only resolved by keeping both sides.
+    fn resolve_conflicts(&self, index: &mut git2::Index, resolve: Resolver) -> Result<Vec<PathBuf>> {
+        let conflicts: Vec<git2::IndexConflict> = index.conflicts()?
+            .collect::<Result<_, _>>()?;
+
+        let mut unresolved = vec!();
+
+        for conflict in conflicts {
+            let blob = |entry: &Option<git2::IndexEntry>| -> Result<Option<Vec<u8>>> {
+                match entry {
+                    Some(entry) => Ok(Some(self.repo.find_blob(entry.id)?.content().to_owned())),
+                    None => Ok(None)
+                }
+            };
+
+            let ancestor = blob(&conflict.ancestor)?;
+            let ours = blob(&conflict.our)?;
+            let theirs = blob(&conflict.their)?;
+
+            let mut entry = conflict.our
+                .or(conflict.their)
+                .or(conflict.ancestor)
+                .context("Conflict with no entries")?;
+            // Resolved entries go in stage 0
+            entry.flags &= !INDEX_STAGE_MASK;
+
+            let path = PathBuf::from(String::from_utf8(entry.path.clone())?);
+            index.remove_path(&path)?;
+
+            let resolution = resolve(ancestor.as_deref(), ours.as_deref(), theirs.as_deref());
+
+            if let Some(content) = resolution.content {
+                self.add_blob(index, &mut entry, &content)?;
+            }
+
+            if let Some(theirs) = resolution.theirs {
+                let mut theirs_path = path.clone().into_os_string();
+                theirs_path.push(".theirs");
+                entry.path = theirs_path.to_str()
+                    .context("Non UTF-8 path")?
+                    .as_bytes()
+                    .to_owned();
+                self.add_blob(index, &mut entry, &theirs)?;
+            }
+
+            if resolution.conflicted {
+                log::warn!("conflict in {}, kept both versions", path.display());
+                unresolved.push(path);
+            }
+        }
+
+        Ok(unresolved)
+    }
+
+    /// Write `content` as a blob and add it to an in-memory merge index
+    fn add_blob(&self, index: &mut git2::Index, entry: &mut git2::IndexEntry, content: &[u8]) -> Result<()> {
+        entry.id = self.repo.blob(content)?;
+        entry.file_size = content.len() as u32;
+        index.add(entry)?;
+        Ok(())
+    }
+
+    pub fn push(&self, remote: &str) -> Result<()> {
+        let branch = self.branch()?;
+        let mut remote = self.repo.find_remote(remote)
+            .with_context(|| format!("Failed to find remote: {}", remote))?;
+
+        let mut callbacks = callbacks();
+        callbacks.push_update_reference(|refname, status| {
+            match status {
+                Some(msg) => Err(git2::Error::from_str(&format!("{} rejected: {}", refname, msg))),
+                None => Ok(())
+            }
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch);
+        remote.push(&[refspec.as_str()], Some(&mut options))
+            .with_context(|| format!("Failed to push to {}", remote.name().unwrap_or("remote")))
+    }
+
+    /// The configured git identity, or a generic one if none is configured
+    fn signature(&self) -> Result<Signature<'static>> {
+        self.repo.signature()
+            .or_else(|_| Signature::now("notewell", "notewell@localhost"))
+            .context("Failed to create commit signature")
+    }
+}
+
+/// Result of merging a remote branch into HEAD
+#[derive(Debug, PartialEq)]
+pub enum Merge {
+    UpToDate,
+    FastForward(Oid),
+    Merged { commit: Oid, conflicts: Vec<PathBuf> },
+}
+
+/// Resolves a conflicted file given its ancestor, our and their contents
+pub type Resolver = fn(Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) -> crate::merge::Resolution;
+
+/// Authenticate with the ssh agent or the configured git credential helper
+fn callbacks<'a>() -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            let config = git2::Config::open_default()?;
+            Cred::credential_helper(&config, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+    callbacks
 }
 
 #[cfg(test)]
@@ -187,7 +491,7 @@ mod tests {
 
 
         let mut index = repo.index()?;
-        c.write("wat".as_bytes())?;
+        c.write_all("wat".as_bytes())?;
         index.add_path(std::path::Path::new("c"))?;
         index.add_path(std::path::Path::new("d"))?;
         let tree_id = index.write_tree()?;
@@ -200,20 +504,21 @@ mod tests {
         println!("{:?}", nb.diff(Some(&oid.to_string()), None)?);
         println!("{:?}", nb_none.diff(Some(&oid.to_string()), None)?);
 
-        println!("{:?}\n", testnotes_dir.path());
+        println!("{:?}", testnotes_dir.path());
+        println!();
 
 
         for reference in repo.references()?.names() {
-            print!("ref: {:?}\n", reference);
+            println!("ref: {:?}", reference);
         }
 
-        print!("head: {:?}\n", repo.revparse_single("HEAD")?.peel_to_tree());
-        print!("head~3: {:?}\n", repo.revparse_single("HEAD~2")?.peel_to_tree());
+        println!("head: {:?}", repo.revparse_single("HEAD")?.peel_to_tree());
+        println!("head~3: {:?}", repo.revparse_single("HEAD~2")?.peel_to_tree());
 
         let tree_a = repo.revparse_single("HEAD")?.peel_to_tree()?;
         let diff = repo.diff_tree_to_workdir_with_index(Some(&tree_a), None)?;
 
-        print!("Deltas: {:?}\n", diff.deltas().len());
+        println!("Deltas: {:?}", diff.deltas().len());
 
         for delta in diff.deltas() {
             println!("{:?}\n", delta);
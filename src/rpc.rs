@@ -0,0 +1,433 @@
+//! JSON-RPC 2.0 over a Unix socket, one message per line. Lets long-running
+//! processes (`nb watch`, `nb serve`, `nb lsp`, `nb http`) serve other nb
+//! commands and editor plugins from an already open heap.
+//!
+//! Methods, with card paths relative to the heap:
+//!
+//! | method        | params                  | result                      |
+//! |---------------|-------------------------|-----------------------------|
+//! | `search`      | `query`                 | matching cards              |
+//! | `show`        | `path`                  | `{path, content}`           |
+//! | `add`         | `content`, `path`?      | path of the new card        |
+//! | `edit-commit` | `path`                  | null                        |
+//! | `capture`     | `text`, `new`?          | path of the card written    |
+//! | `append`      | `path`, `text`          | null                        |
+//! | `prepend`     | `path`, `text`          | null                        |
+//! | `sync`        |                         | null                        |
+//! | `conflicts`   |                         | conflicted cards            |
+//! | `tags`        |                         | `{tag: count}`              |
+//! | `links`       | `path`                  | links with resolved paths   |
+//! | `backlinks`   | `path`                  | cards linking to `path`     |
+//! | `attach`      | `path`, `file`          | path of the attachment      |
+//! | `save-query`  | `name`, `query`         | null                        |
+//! | `fsck`        | `fix`?                  | what's wrong with the index |
+//! | `import`      | `format`, `source`      | what was imported           |
+//! | `add-remote`  | `name`, `url`           | null                        |
+//! | `pull`        | `remote`                | cards left with conflicts   |
+//! | `push`        | `remote`                | null                        |
+//! | `sync-remote` | `remote`                | cards left with conflicts   |
+//!
+//! `file` and `source` are read by the server, so must be absolute.
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::capture;
+use crate::heap::Heap;
+use crate::import;
+use crate::index::QueryResult;
+
+/// Socket `nb watch` listens on, relative to the heap's `.nb` directory
+const SOCKET_NAME: &str = "nb.sock";
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_ERROR: i64 = -32000;
+
+pub fn socket_path<P: AsRef<Path>>(heap_path: P) -> PathBuf {
+    heap_path.as_ref().join(".nb").join(SOCKET_NAME)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Response {
+        Response { jsonrpc: "2.0".to_owned(), id, result: Some(result), error: None }
+    }
+
+    fn error(id: Value, code: i64, message: String) -> Response {
+        Response { jsonrpc: "2.0".to_owned(), id, result: None, error: Some(RpcError { code, message }) }
+    }
+}
+
+/// Listens on a Unix socket, serving requests from a shared heap. The socket
+/// file is removed when the server is dropped.
+pub struct Server {
+    path: PathBuf,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Server {
+
+    /// Start serving `heap` on `path` from a background thread
+    pub fn spawn<P: AsRef<Path>>(heap: Arc<Mutex<Heap>>, path: P) -> Result<Server> {
+        let path = path.as_ref().to_owned();
+
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                bail!("Another nb process is already serving {}", path.display());
+            }
+            // Left behind by a process that didn't shut down cleanly
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        log::info!("listening on {}", path.display());
+
+        let thread = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let heap = heap.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = handle(stream, heap) {
+                                log::warn!("rpc connection failed: {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("rpc accept failed: {}", e),
+                }
+            }
+        });
+
+        Ok(Server { path, thread: Some(thread) })
+    }
+
+    /// Serve until the listener fails
+    pub fn join(mut self) -> Result<()> {
+        if let Some(thread) = self.thread.take() {
+            thread.join().map_err(|_| anyhow::anyhow!("rpc server panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn handle(stream: UnixStream, heap: Arc<Mutex<Heap>>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let mut heap = heap.lock().expect("heap lock poisoned");
+                match dispatch(&mut heap, &request.method, request.params) {
+                    Ok(result) => Response::result(request.id, result),
+                    Err(e) => match e.downcast_ref::<UnknownMethod>() {
+                        Some(_) => Response::error(request.id, METHOD_NOT_FOUND, e.to_string()),
+                        None => Response::error(request.id, SERVER_ERROR, format!("{:#}", e)),
+                    }
+                }
+            }
+            Err(e) => Response::error(Value::Null, PARSE_ERROR, e.to_string()),
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown method: {0}")]
+struct UnknownMethod(String);
+
+fn param<T: DeserializeOwned>(params: &Value, name: &str) -> Result<T> {
+    let value = params.get(name)
+        .with_context(|| format!("missing parameter: {}", name))?;
+    serde_json::from_value(value.clone())
+        .with_context(|| format!("invalid parameter: {}", name))
+}
+
+/// A path the server reads from outside the heap, which can't be relative
+/// as the server's working directory isn't the caller's
+fn absolute_param(params: &Value, name: &str) -> Result<PathBuf> {
+    let path: PathBuf = param(params, name)?;
+    if !path.is_absolute() {
+        bail!("invalid parameter: {} must be absolute", name);
+    }
+    Ok(path)
+}
+
+fn dispatch(heap: &mut Heap, method: &str, params: Value) -> Result<Value> {
+    match method {
+        "search" => {
+            let query: String = param(&params, "query")?;
+            Ok(serde_json::to_value(heap.find(&query)?)?)
+        }
+        "sync" => {
+            heap.sync()?;
+            Ok(Value::Null)
+        }
+        "show" => {
+            let path: PathBuf = param(&params, "path")?;
+            let content = heap.read_card(&path)?;
+            Ok(json!({ "path": path, "content": content }))
+        }
+        "add" => {
+            let content: String = param(&params, "content")?;
+            let path: Option<PathBuf> = param(&params, "path").ok();
+            Ok(json!(heap.create_card(path, &content)?))
+        }
+        "capture" => {
+            let text: String = param(&params, "text")?;
+            let new: bool = param(&params, "new").unwrap_or(false);
+            Ok(json!(capture::capture(heap, &text, new)?))
+        }
+        "append" | "prepend" => {
+            let path: PathBuf = param(&params, "path")?;
+            let text: String = param(&params, "text")?;
+            if method == "append" {
+                heap.append_card(&path, &text)?;
+            } else {
+                heap.prepend_card(&path, &text)?;
+            }
+            Ok(Value::Null)
+        }
+        "conflicts" => Ok(serde_json::to_value(heap.conflicts()?)?),
+        "tags" => Ok(serde_json::to_value(heap.tags()?)?),
+        "links" => {
+            let path: PathBuf = param(&params, "path")?;
+            Ok(serde_json::to_value(heap.links(&path)?)?)
+        }
+        "backlinks" => {
+            let path: PathBuf = param(&params, "path")?;
+            Ok(serde_json::to_value(heap.backlinks(&path)?)?)
+        }
+        "edit-commit" => {
+            let path: PathBuf = param(&params, "path")?;
+            heap.commit_card(&path)?;
+            Ok(Value::Null)
+        }
+        "attach" => {
+            let path: PathBuf = param(&params, "path")?;
+            let file = absolute_param(&params, "file")?;
+            Ok(json!(heap.attach(&path, &file)?))
+        }
+        "save-query" => {
+            let name: String = param(&params, "name")?;
+            let query: String = param(&params, "query")?;
+            heap.save_query(&name, &query)?;
+            Ok(Value::Null)
+        }
+        "fsck" => {
+            let fix: bool = param(&params, "fix").unwrap_or(false);
+            Ok(serde_json::to_value(heap.fsck(fix)?)?)
+        }
+        "import" => {
+            let format: String = param(&params, "format")?;
+            let source = absolute_param(&params, "source")?;
+            Ok(serde_json::to_value(import::import(heap, &format, &source)?)?)
+        }
+        "add-remote" => {
+            let name: String = param(&params, "name")?;
+            let url: String = param(&params, "url")?;
+            heap.add_remote(&name, &url)?;
+            Ok(Value::Null)
+        }
+        "pull" => {
+            let remote: String = param(&params, "remote")?;
+            Ok(json!(heap.pull(&remote)?))
+        }
+        "push" => {
+            let remote: String = param(&params, "remote")?;
+            heap.push(&remote)?;
+            Ok(Value::Null)
+        }
+        "sync-remote" => {
+            let remote: String = param(&params, "remote")?;
+            heap.sync()?;
+            Ok(json!(heap.sync_remote(&remote)?))
+        }
+        _ => Err(UnknownMethod(method.to_owned()).into()),
+    }
+}
+
+/// Call `method` on the process serving the heap at `heap_path` if there is
+/// one, or else open the heap and run it here. Commands that write go
+/// through this, so they still work while `nb watch` holds the heap.
+pub fn call_or_open<P: AsRef<Path>, T: DeserializeOwned>(heap_path: P, method: &str, params: Value) -> Result<T> {
+    let result = match Client::connect(&heap_path) {
+        Some(mut client) => client.call(method, params)?,
+        None => dispatch(&mut Heap::open(heap_path)?, method, params)?,
+    };
+    Ok(serde_json::from_value(result)?)
+}
+
+/// Connection to a running `nb watch` or `nb serve`
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Client {
+
+    /// Connect to the server for the heap at `heap_path`, if one is running
+    pub fn connect<P: AsRef<Path>>(heap_path: P) -> Option<Client> {
+        let stream = UnixStream::connect(socket_path(heap_path)).ok()?;
+        let writer = stream.try_clone().ok()?;
+        Some(Client { reader: BufReader::new(stream), writer, next_id: 0 })
+    }
+
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let request = Request {
+            jsonrpc: "2.0".to_owned(),
+            id: json!(self.next_id),
+            method: method.to_owned(),
+            params,
+        };
+
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line).context("Failed to read response")?;
+        let response: Response = serde_json::from_str(&line)
+            .context("Invalid response from server")?;
+
+        match (response.result, response.error) {
+            (_, Some(error)) => bail!("{}", error.message),
+            (Some(result), None) => Ok(result),
+            (None, None) => Ok(Value::Null),
+        }
+    }
+
+    pub fn search(&mut self, query: &str) -> Result<Vec<QueryResult>> {
+        let results = self.call("search", json!({ "query": query }))?;
+        Ok(serde_json::from_value(results)?)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.call("sync", Value::Null).map(|_| ())
+    }
+
+    pub fn conflicts(&mut self) -> Result<Vec<QueryResult>> {
+        Ok(serde_json::from_value(self.call("conflicts", Value::Null)?)?)
+    }
+
+    pub fn edit_commit<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.call("edit-commit", json!({ "path": path.as_ref() })).map(|_| ())
+    }
+
+    /// Append `text` to the card at `path`, or with `prepend` add it to the start
+    pub fn append<P: AsRef<Path>>(&mut self, path: P, text: &str, prepend: bool) -> Result<()> {
+        let method = if prepend { "prepend" } else { "append" };
+        self.call(method, json!({ "path": path.as_ref(), "text": text })).map(|_| ())
+    }
+
+    pub fn capture(&mut self, text: &str, new: bool) -> Result<PathBuf> {
+        Ok(serde_json::from_value(self.call("capture", json!({ "text": text, "new": new }))?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serve_heap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("heap");
+        let heap = Heap::init(&path)?;
+
+        let heap = Arc::new(Mutex::new(heap));
+        let _server = Server::spawn(heap, socket_path(&path))?;
+
+        let mut client = Client::connect(&path).expect("server should be running");
+        std::fs::write(path.join("a.md"), "apples")?;
+        client.edit_commit("a.md")?;
+        assert_eq!(client.search("apples")?.len(), 1);
+        assert!(client.edit_commit("../outside.md").is_err());
+        assert!(client.edit_commit(path.join("a.md")).is_err());
+        assert!(client.edit_commit(".git/config").is_err());
+        assert!(client.call("add", json!({ "path": ".nb/x.md", "content": "x" })).is_err());
+        assert!(!path.join(".nb/x.md").exists());
+
+        let b = client.call("add", json!({ "content": "---\ntags: fruit\n---\nlike [[a]] and [[c]]" }))?;
+        let b = b.as_str().unwrap();
+        assert!(b.ends_with(".md"));
+        assert_eq!(client.call("show", json!({ "path": b }))?["content"], "---\ntags: fruit\n---\nlike [[a]] and [[c]]");
+        assert_eq!(client.call("tags", Value::Null)?, json!({ "fruit": 1 }));
+
+        let links = client.call("links", json!({ "path": b }))?;
+        assert_eq!(links[0]["path"], "a.md");
+        assert_eq!(links[1]["path"], Value::Null);
+
+        let backlinks = client.call("backlinks", json!({ "path": "a.md" }))?;
+        assert_eq!(backlinks[0]["card"]["path"], b);
+
+        let file = dir.path().join("plan.txt");
+        std::fs::write(&file, "day one")?;
+        assert!(client.call("attach", json!({ "path": "a.md", "file": "plan.txt" })).is_err());
+        let attachment = client.call("attach", json!({ "path": "a.md", "file": file }))?;
+        assert!(path.join(attachment.as_str().unwrap()).exists());
+
+        client.call("save-query", json!({ "name": "fruit", "query": "tags:fruit" }))?;
+        assert_eq!(client.search("saved:fruit")?.len(), 1);
+        let report: crate::heap::FsckReport = serde_json::from_value(client.call("fsck", Value::Null)?)?;
+        assert!(report.is_clean(), "{:?}", report);
+
+        // With the heap held, commands that write are served rather than failing
+        call_or_open::<_, ()>(&path, "save-query", json!({ "name": "apples", "query": "apples" }))?;
+        assert_eq!(client.search("saved:apples")?.len(), 1);
+
+        let err = client.call("frobnicate", Value::Null).unwrap_err();
+        assert_eq!(err.to_string(), "unknown method: frobnicate");
+
+        Ok(())
+    }
+}
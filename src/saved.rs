@@ -0,0 +1,128 @@
+//! Saved searches, kept by name in `.nbqueries` at the heap root so they're
+//! committed, and pulled and pushed, along with the cards:
+//!
+//! ```toml
+//! work = "tags:work -tags:done"
+//! reading = "tags:book OR tags:paper"
+//! ```
+//!
+//! `saved:<name>` in a query stands for the saved query `name`, so saved
+//! queries can build on each other.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const QUERIES_FILE: &str = ".nbqueries";
+
+/// Term standing for a saved query, followed by its name
+const TERM: &str = "saved:";
+
+/// Saved queries by name
+pub type Queries = BTreeMap<String, String>;
+
+pub fn load(heap: &Path) -> Result<Queries> {
+    let path = heap.join(QUERIES_FILE);
+    let queries = match std::fs::read_to_string(&path) {
+        Ok(queries) => queries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Queries::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    toml::from_str(&queries).with_context(|| format!("Failed to decode {}", path.display()))
+}
+
+pub fn store(heap: &Path, queries: &Queries) -> Result<()> {
+    std::fs::write(heap.join(QUERIES_FILE), toml::to_string(queries)?)?;
+    Ok(())
+}
+
+/// Names are kept to what can follow `saved:` in a query
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        bail!("Invalid query name, use letters, digits, - and _: {}", name);
+    }
+    Ok(())
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Whether `query` names any saved queries, so searches that don't can skip
+/// loading them
+pub fn is_used(query: &str) -> bool {
+    find_term(query).is_some()
+}
+
+/// `query` with each `saved:<name>` replaced by the saved query, in brackets
+pub fn expand(query: &str, queries: &Queries) -> Result<String> {
+    expand_within(query, queries, &mut vec!())
+}
+
+/// `expand`, inside the saved queries named in `within`
+fn expand_within(query: &str, queries: &Queries, within: &mut Vec<String>) -> Result<String> {
+    let mut out = String::with_capacity(query.len());
+    let mut rest = query;
+
+    while let Some(start) = find_term(rest) {
+        let after = &rest[start + TERM.len()..];
+        let len = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+        let name = &after[..len];
+
+        if within.iter().any(|n| n == name) {
+            bail!("Saved query {} refers to itself", name);
+        }
+        let saved = queries.get(name)
+            .with_context(|| format!("No saved query named {}", name))?;
+
+        within.push(name.to_owned());
+        let saved = expand_within(saved, queries, within)?;
+        within.pop();
+
+        out.push_str(&rest[..start]);
+        out.push('(');
+        out.push_str(&saved);
+        out.push(')');
+        rest = &after[len..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Offset of the first `saved:` term in `query`, ignoring it where it's
+/// only the end of a longer word
+fn find_term(query: &str) -> Option<usize> {
+    query.match_indices(TERM)
+        .map(|(start, _)| start)
+        .find(|&start| match query[..start].chars().last() {
+            None => true,
+            Some(c) => c.is_whitespace() || "(+-".contains(c),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_saved() -> Result<()> {
+        let mut queries = Queries::new();
+        queries.insert("work".to_owned(), "tags:work".to_owned());
+        queries.insert("open-work".to_owned(), "saved:work -tags:done".to_owned());
+        queries.insert("loop".to_owned(), "a OR saved:loop".to_owned());
+
+        assert_eq!(expand("saved:open-work OR -saved:work", &queries)?,
+            "((tags:work) -tags:done) OR -(tags:work)");
+        assert_eq!(expand("unsaved:work", &queries)?, "unsaved:work");
+        assert!(is_used("a OR (saved:work)"));
+        assert!(!is_used("unsaved:work"));
+        assert!(expand("saved:missing", &queries).is_err());
+        assert!(expand("saved:loop", &queries).is_err());
+
+        assert!(check_name("open-work").is_ok());
+        assert!(check_name("open work").is_err());
+
+        Ok(())
+    }
+}
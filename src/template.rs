@@ -0,0 +1,192 @@
+//! Templates for new cards, kept in the heap as `templates/<name>.md` and
+//! used with `nb add --template <name>`. Placeholders in double braces are
+//! filled in before the editor opens:
+//!
+//! - `{{date}}`: today, e.g. 2020-01-31
+//! - `{{title}}`: from `--title`, or asked for
+//! - `{{id}}`: the new card's ULID, which is also its path unless one is given
+//! - `{{clipboard}}`: the clipboard's text, empty outside a desktop session,
+//!   such as over ssh
+//! - `{{prompt:Attendees}}`: asked for, once however often it appears
+//!
+//! Anything else in double braces is left as it is.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::heap::Heap;
+use crate::journal;
+
+pub const TEMPLATES_DIR: &str = "templates";
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+const PROMPT: &str = "prompt:";
+
+/// Commands that print the clipboard, tried in turn
+const CLIPBOARD_COMMANDS: &[&[&str]] = &[
+    &["pbpaste"],
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-out"],
+    &["xsel", "--clipboard", "--output"],
+    &["powershell", "-NoProfile", "-Command", "Get-Clipboard"],
+];
+
+/// Whether `path`, relative to the heap, is a template rather than a card
+pub fn is_template(path: &Path) -> bool {
+    path.starts_with(TEMPLATES_DIR)
+}
+
+/// Contents of the template `name`
+pub fn load(heap: &Heap, name: &str) -> Result<String> {
+    if Path::new(name).components().count() != 1 {
+        bail!("Invalid template name: {}", name);
+    }
+    let path = Path::new(TEMPLATES_DIR).join(format!("{}.md", name));
+    std::fs::read_to_string(heap.path().join(&path))
+        .with_context(|| format!("No template {}, looked for {}", name, path.display()))
+}
+
+/// Fill in the placeholders in `template` with what `value` gives for
+/// their names. Each name is looked up once; those it gives nothing for
+/// are left as they are.
+pub fn render<F>(template: &str, mut value: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<Option<String>>,
+{
+    let mut values: HashMap<&str, Option<String>> = HashMap::new();
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        let end = match rest[start..].find(CLOSE) {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = rest[start + OPEN.len()..end].trim();
+
+        if !values.contains_key(name) {
+            values.insert(name, value(name)?);
+        }
+        out.push_str(&rest[..start]);
+        match &values[name] {
+            Some(value) => out.push_str(value),
+            None => out.push_str(&rest[start..end + CLOSE.len()]),
+        }
+        rest = &rest[end + CLOSE.len()..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Open a new card from the template `name` in the editor, then commit and
+/// index it
+pub fn add(heap: &mut Heap, name: &str, path: Option<&str>, title: Option<&str>) -> Result<PathBuf> {
+    let (path, content) = card(heap, name, path, title)?;
+    heap.add_card_from(Some(path), &content)
+}
+
+/// Path and content of a new card from the template `name`, asking on the
+/// terminal for any values it prompts for
+pub fn card(heap: &Heap, name: &str, path: Option<&str>, title: Option<&str>) -> Result<(PathBuf, String)> {
+    let template = load(heap, name)?;
+    let id = rusty_ulid::generate_ulid_string();
+
+    let content = render(&template, |name| Ok(match name {
+        "date" => Some(journal::today().format(journal::DATE_FORMAT).to_string()),
+        "id" => Some(id.clone()),
+        "title" => Some(match title {
+            Some(title) => title.to_owned(),
+            None => prompt("Title")?,
+        }),
+        "clipboard" => Some(clipboard()),
+        name => match name.strip_prefix(PROMPT) {
+            Some(label) => Some(prompt(label.trim())?),
+            None => None,
+        },
+    }))?;
+
+    let path = path.map(PathBuf::from).unwrap_or_else(|| PathBuf::from(format!("{}.md", id)));
+    Ok((path, content))
+}
+
+/// Ask for a value on the terminal
+fn prompt(label: &str) -> Result<String> {
+    eprint!("{}: ", label);
+    std::io::stderr().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Text on the clipboard, or nothing if there's no clipboard to read
+fn clipboard() -> String {
+    if !has_display() {
+        log::warn!("No display, leaving {{{{clipboard}}}} empty");
+        return String::new();
+    }
+
+    for command in CLIPBOARD_COMMANDS {
+        let output = Command::new(command[0]).args(&command[1..]).output();
+        if let Ok(output) = output {
+            if output.status.success() {
+                return String::from_utf8_lossy(&output.stdout).into_owned();
+            }
+        }
+    }
+
+    log::warn!("No clipboard available, leaving {{{{clipboard}}}} empty");
+    String::new()
+}
+
+/// Whether this is a desktop session with a clipboard. Elsewhere the
+/// clipboard commands may be missing, or wait for a display that isn't there.
+fn has_display() -> bool {
+    cfg!(any(target_os = "macos", windows))
+        || ["DISPLAY", "WAYLAND_DISPLAY"].iter().any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_placeholders() -> Result<()> {
+        let template = "# {{title}}\n{{ date }} {{prompt:Who}} {{unknown}} {{prompt:Who}} {{title";
+        let mut asked = vec!();
+        let out = render(template, |name| {
+            asked.push(name.to_owned());
+            Ok(match name {
+                "title" => Some("Standup".to_owned()),
+                "date" => Some("2020-01-31".to_owned()),
+                "prompt:Who" => Some("Ann, Bo".to_owned()),
+                _ => None,
+            })
+        })?;
+
+        assert_eq!(out, "# Standup\n2020-01-31 Ann, Bo {{unknown}} Ann, Bo {{title");
+        assert_eq!(asked, vec!["title", "date", "prompt:Who", "unknown"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn templates_arent_cards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        heap.create_card(Some("templates/meeting.md"), "# {{title}}\n\n## Attendees\n")?;
+
+        assert!(load(&heap, "meeting")?.starts_with("# {{title}}"));
+        assert!(load(&heap, "incident").is_err());
+        assert!(load(&heap, "../meeting").is_err());
+        assert!(heap.cards()?.is_empty());
+        assert!(heap.find("attendees")?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! Note text from the bytes of a file. Notes should be UTF-8, but older
+//! ones may be in a legacy encoding such as Windows-1252 or Shift JIS, and
+//! a heap may hold binary files that aren't notes at all.
+
+use encoding_rs::Encoding;
+
+/// How much of a file to look at for NUL bytes, as git does
+const BINARY_CHECK_LEN: usize = 8000;
+
+#[derive(Debug, PartialEq)]
+pub enum Text {
+    Utf8(String),
+    /// Decoded from the named encoding, with anything it couldn't decode
+    /// replaced by U+FFFD
+    Decoded(String, &'static str),
+    Binary,
+}
+
+impl Text {
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Text::Utf8(text) | Text::Decoded(text, _) => Some(text),
+            Text::Binary => None,
+        }
+    }
+}
+
+/// Decode `data`, detecting its encoding if it isn't UTF-8
+pub fn decode(data: &[u8]) -> Text {
+    // A byte order mark is unambiguous, and UTF-16 is full of NULs
+    if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+        let (text, _) = encoding.decode_without_bom_handling(&data[bom_len..]);
+        return Text::Decoded(text.into_owned(), encoding.name());
+    }
+
+    if data[..data.len().min(BINARY_CHECK_LEN)].contains(&0) {
+        return Text::Binary;
+    }
+
+    if let Ok(text) = std::str::from_utf8(data) {
+        return Text::Utf8(text.to_owned());
+    }
+
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(data, true);
+    let encoding = detector.guess(None, false);
+    let (text, _) = encoding.decode_without_bom_handling(data);
+    Text::Decoded(text.into_owned(), encoding.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_encodings() {
+        assert_eq!(decode("caf\u{e9}".as_bytes()), Text::Utf8("caf\u{e9}".to_owned()));
+        assert_eq!(decode(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Text::Binary);
+        assert_eq!(decode(b"\xef\xbb\xbfbom"), Text::Decoded("bom".to_owned(), "UTF-8"));
+        assert_eq!(decode(b"\xff\xfeh\0i\0"), Text::Decoded("hi".to_owned(), "UTF-16LE"));
+
+        let latin1 = b"Le caf\xe9 est tr\xe8s bon, na\xefve cr\xe8me br\xfbl\xe9e \x96 d\xe9j\xe0 vu";
+        assert_eq!(decode(latin1),
+            Text::Decoded("Le caf\u{e9} est tr\u{e8}s bon, na\u{ef}ve cr\u{e8}me br\u{fb}l\u{e9}e \u{2013} d\u{e9}j\u{e0} vu".to_owned(), "windows-1252"));
+    }
+}
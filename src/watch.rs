@@ -0,0 +1,174 @@
+//! `nb watch`: keep the index live as cards change on disk, and serve other
+//! nb commands over the heap's socket while running.
+
+use anyhow::{Context, Result};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::heap::Heap;
+use crate::rpc;
+
+/// Quiet period before a burst of changes is processed
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Changes collected from one burst of filesystem events
+#[derive(Debug, Default)]
+struct Changes {
+    cards: BTreeSet<PathBuf>, // Card paths relative to the heap
+    repo: bool,               // Something under .git changed, e.g. a commit
+}
+
+impl Changes {
+    fn add(&mut self, root: &Path, event: DebouncedEvent) {
+        match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Remove(path) => self.add_path(root, &path),
+            DebouncedEvent::Rename(from, to) => {
+                self.add_path(root, &from);
+                self.add_path(root, &to);
+            }
+            DebouncedEvent::Rescan => self.repo = true,
+            DebouncedEvent::Error(e, path) => log::warn!("watch error on {:?}: {}", path, e),
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Chmod(_) => {}
+        }
+    }
+
+    fn add_path(&mut self, root: &Path, path: &Path) {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return,
+        };
+
+        let mut components = relative.components().map(|c| c.as_os_str().to_string_lossy());
+
+        match components.next().as_deref() {
+            Some(".git") => self.repo = true,
+            // Our own state, including the index we're about to write
+            Some(".nb") | None => {}
+            _ => {
+                // Hidden files and editor backups, e.g. .card.md.swp or card.md~
+                let hidden = relative.components()
+                    .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+                let backup = relative.to_string_lossy().ends_with('~');
+                if !hidden && !backup {
+                    self.cards.insert(relative.to_owned());
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cards.is_empty() && !self.repo
+    }
+}
+
+/// Watch the heap until interrupted. With `commit`, changed cards are
+/// committed as they are saved; otherwise only new commits are indexed.
+pub fn watch(heap: Heap, commit: bool) -> Result<()> {
+    let root = heap.path().to_owned();
+    let heap = Arc::new(Mutex::new(heap));
+    let _server = rpc::Server::spawn(heap.clone(), rpc::socket_path(&root))?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE).context("Failed to start file watcher")?;
+    watcher.watch(&root, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", root.display()))?;
+
+    println!("watching {}", root.display());
+
+    loop {
+        let mut changes = Changes::default();
+        changes.add(&root, rx.recv().context("File watcher stopped")?);
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            changes.add(&root, event);
+        }
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        // Keep watching; the next change will retry
+        if let Err(e) = update(&mut heap.lock().expect("heap lock poisoned"), &changes, commit) {
+            log::error!("failed to update index: {:?}", e);
+        }
+    }
+}
+
+/// Index one burst of changes, with `commit` committing the cards and
+/// attachments saved or removed first. Directories and other files changed
+/// along with them are left alone.
+fn update(heap: &mut Heap, changes: &Changes, commit: bool) -> Result<()> {
+    if !commit || changes.cards.is_empty() {
+        return heap.sync();
+    }
+
+    let paths: Vec<&PathBuf> = changes.cards.iter()
+        .filter(|path| {
+            let full_path = heap.path().join(path);
+            (full_path.is_file() || !full_path.exists())
+                && heap.is_indexable(path)
+                && !heap.is_ignored(path)
+        })
+        .collect();
+    heap.commit_paths(&paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_changes() {
+        let root = Path::new("/heap");
+        let mut changes = Changes::default();
+
+        changes.add(root, DebouncedEvent::Write(root.join("a.md")));
+        changes.add(root, DebouncedEvent::Create(root.join(".a.md.swp")));
+        changes.add(root, DebouncedEvent::Write(root.join("a.md~")));
+        changes.add(root, DebouncedEvent::Write(root.join(".nb/index/meta.json")));
+        assert_eq!(changes.cards.iter().collect::<Vec<_>>(), vec![Path::new("a.md")]);
+        assert!(!changes.repo);
+
+        changes.add(root, DebouncedEvent::Write(root.join(".git/refs/heads/master")));
+        assert!(changes.repo);
+    }
+
+    #[test]
+    fn commit_changes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut heap = Heap::init(dir.path().join("heap"))?;
+        let root = heap.path().to_owned();
+
+        // New folders arrive in the same burst as the files saved in them
+        std::fs::create_dir_all(root.join("attachments/01"))?;
+        std::fs::write(root.join("attachments/01/plan.txt"), "day one")?;
+        std::fs::create_dir(root.join("trips"))?;
+        std::fs::write(root.join("trips/rome.md"), "colosseum")?;
+        std::fs::write(root.join("a.md"), "apples")?;
+        let mut changes = Changes::default();
+        for path in &["attachments", "attachments/01", "attachments/01/plan.txt", "trips", "trips/rome.md", "a.md"] {
+            changes.add(&root, DebouncedEvent::Create(root.join(path)));
+        }
+        update(&mut heap, &changes, true)?;
+        assert_eq!(heap.find("colosseum")?.len(), 1);
+        assert_eq!(heap.find("apples")?.len(), 1);
+        let repo = git2::Repository::open(&root)?;
+        let tree = repo.head()?.peel_to_tree()?;
+        assert!(tree.get_path(Path::new("attachments/01/plan.txt")).is_ok());
+
+        std::fs::remove_file(root.join("a.md"))?;
+        let mut changes = Changes::default();
+        changes.add(&root, DebouncedEvent::Remove(root.join("a.md")));
+        update(&mut heap, &changes, true)?;
+        assert!(heap.find("apples")?.is_empty());
+
+        Ok(())
+    }
+}